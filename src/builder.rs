@@ -0,0 +1,204 @@
+use crate::{Config, Error, Passthrough, Proxy};
+use log::info;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::path::Path;
+use std::time::Duration;
+
+/// Which port the server listens on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PortChoice {
+    /// Try this port first, falling back to one picked by the OS if it is taken
+    Preferred(u16),
+    /// Only this port, failing if it is taken
+    Exact(u16),
+    /// Any free port picked by the OS
+    Ephemeral,
+}
+
+impl Config {
+    /// Address the server tries to listen on first, with port 0 meaning any free port
+    pub(crate) const fn requested_address(&self) -> SocketAddr {
+        let port = match self.port {
+            PortChoice::Preferred(port) | PortChoice::Exact(port) => port,
+            PortChoice::Ephemeral => 0,
+        };
+        SocketAddr::new(self.bind_address, port)
+    }
+
+    /// Binds a listener according to the configured address and port
+    pub(crate) fn bind(&self) -> io::Result<TcpListener> {
+        match self.port {
+            PortChoice::Preferred(port) => {
+                TcpListener::bind((self.bind_address, port)).or_else(|err| {
+                    info!("Port {} unavailable ({}), using any free port", port, err);
+                    TcpListener::bind((self.bind_address, 0))
+                })
+            }
+            PortChoice::Exact(port) => TcpListener::bind((self.bind_address, port)),
+            PortChoice::Ephemeral => TcpListener::bind((self.bind_address, 0)),
+        }
+    }
+}
+
+/// Configures a [`Proxy`] before it is created
+///
+/// By default the proxy listens on `127.0.0.1`, port 1234 if it is free or any other free
+/// port otherwise
+///
+/// ```
+/// use mock_proxy::Proxy;
+/// use std::net::Ipv6Addr;
+///
+/// let mut proxy = Proxy::builder()
+///     .with_bind_address(Ipv6Addr::LOCALHOST.into())
+///     .with_ephemeral_port()
+///     .build();
+/// proxy.start();
+/// assert!(proxy.address().is_ipv6());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ProxyBuilder {
+    config: Config,
+}
+
+impl ProxyBuilder {
+    /// Builder with the default settings, same as [`Proxy::builder`]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the address the server listens on, e.g. `::1` to listen on IPv6
+    pub const fn with_bind_address(&mut self, address: IpAddr) -> &mut Self {
+        self.config.bind_address = address;
+        self
+    }
+
+    /// Listens on exactly this port
+    ///
+    /// Starting the proxy fails if the port is taken
+    pub const fn with_port(&mut self, port: u16) -> &mut Self {
+        self.config.port = PortChoice::Exact(port);
+        self
+    }
+
+    /// Listens on any free port picked by the OS, never trying the default port 1234
+    ///
+    /// Useful when several test binaries run in parallel. Use [`Proxy::address`] to find
+    /// the port once started
+    pub const fn with_ephemeral_port(&mut self) -> &mut Self {
+        self.config.port = PortChoice::Ephemeral;
+        self
+    }
+
+    /// Sets how long a kept-alive connection may sit idle before it is closed
+    ///
+    /// Writing a response gives up after as long without progress, when a client stops
+    /// reading it. Default is 5 seconds
+    pub const fn with_idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.config.idle_timeout = timeout;
+        self
+    }
+
+    /// Sets how many worker threads are kept for handling connections
+    ///
+    /// Default is 8. A connection arriving while every worker is busy, such as with idle
+    /// kept-alive connections, gets a thread of its own that ends with it
+    pub const fn with_worker_threads(&mut self, threads: usize) -> &mut Self {
+        self.config.worker_threads = threads;
+        self
+    }
+
+    /// Waits before sending every response of a mock without a delay of its own
+    ///
+    /// Default is no delay
+    pub const fn with_default_delay(&mut self, delay: Duration) -> &mut Self {
+        self.config.default_delay = delay;
+        self
+    }
+
+    /// Forwards requests that match no mock, instead of answering them with an error
+    ///
+    /// Default is [`Passthrough::None`]. Forwarded requests go to the host they were meant
+    /// for, or to the server set with [`ProxyBuilder::with_upstream`]. They are recorded, but
+    /// not counted as unmatched. Those the upstream fails are answered with
+    /// `502 Bad Gateway`, or `504 Gateway Timeout` if it took longer than
+    /// [`ProxyBuilder::with_upstream_timeout`]
+    pub fn passthrough(&mut self, policy: Passthrough) -> &mut Self {
+        self.config.passthrough = policy;
+        self
+    }
+
+    /// Sends every forwarded request to a local stand-in server, in plain HTTP
+    ///
+    /// Only used for requests allowed by [`ProxyBuilder::passthrough`]
+    pub const fn with_upstream(&mut self, address: SocketAddr) -> &mut Self {
+        self.config.upstream = Some(address);
+        self
+    }
+
+    /// Sets how long forwarded requests wait for the upstream to connect, and for each read
+    /// or write after
+    ///
+    /// Default is 30 seconds. Requests it takes longer for are answered with
+    /// `504 Gateway Timeout`
+    pub const fn with_upstream_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.config.upstream_timeout = timeout;
+        self
+    }
+
+    /// Saves every forwarded request and its response as fixture files in `dir`
+    ///
+    /// Only requests allowed by [`ProxyBuilder::passthrough`] are forwarded, and so recorded.
+    /// The fixtures can be turned back into mocks with [`Proxy::replay_from`]. `dir` is
+    /// created when the proxy is started, which fails if it can't be
+    pub fn record_to<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.config.recording = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the largest request head accepted, in bytes
+    ///
+    /// Default is 64 KiB. Requests with larger heads, such as huge cookies, are answered with
+    /// `431 Request Header Fields Too Large`
+    pub const fn with_max_head_size(&mut self, bytes: usize) -> &mut Self {
+        self.config.limits.max_head_size = bytes;
+        self
+    }
+
+    /// Sets how many headers a request may have
+    ///
+    /// Default is 100. Requests with more are answered with
+    /// `431 Request Header Fields Too Large`
+    pub const fn with_max_headers(&mut self, headers: usize) -> &mut Self {
+        self.config.limits.max_headers = headers;
+        self
+    }
+
+    /// Sets the largest request body accepted, in bytes
+    ///
+    /// Default is 16 MiB. Requests with larger bodies, whether announced by their
+    /// `Content-Length` or sent in chunks, are answered with `413 Payload Too Large`
+    pub const fn with_max_body_size(&mut self, bytes: usize) -> &mut Self {
+        self.config.limits.max_body_size = bytes;
+        self
+    }
+
+    /// Creates the configured [`Proxy`], which still has to be started
+    ///
+    /// # Panics
+    /// If the root CA certificate can't be generated, see [`ProxyBuilder::try_build`]
+    pub fn build(&self) -> Proxy {
+        self.try_build().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Creates the configured [`Proxy`], without panicking
+    ///
+    /// # Errors
+    /// If the root CA certificate can't be generated
+    pub fn try_build(&self) -> Result<Proxy, Error> {
+        let mut proxy = Proxy::try_new()?;
+        proxy.config = self.config.clone();
+        Ok(proxy)
+    }
+}
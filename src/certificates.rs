@@ -0,0 +1,90 @@
+use crate::identity::OpensslInterface;
+use crate::identity_interface::{Cert, IdentityInterface};
+use log::info;
+use native_tls::{Identity, TlsAcceptor};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+const PASSWORD: &str = "password";
+
+/// Issues leaf certificates signed by the proxy's root CA, keeping one per hostname
+pub(crate) struct Certificates {
+    ca: Cert,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Private key used for every leaf certificate, instead of one each
+    leaf_key: Option<Vec<u8>>,
+    acceptors: HashMap<String, TlsAcceptor>,
+}
+
+impl Certificates {
+    pub(crate) fn new(ca: Cert) -> Self {
+        Self {
+            ca,
+            state: Mutex::default(),
+        }
+    }
+
+    /// The root CA all leaf certificates are signed by
+    pub(crate) const fn ca(&self) -> &Cert {
+        &self.ca
+    }
+
+    /// Signs every following leaf certificate with a single shared private key
+    ///
+    /// Throws away anything cached so far, as it was issued for other keys
+    pub(crate) fn use_shared_leaf_key(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let leaf_key = OpensslInterface::new().mk_leaf_key()?;
+
+        let mut state = self.state.lock().unwrap();
+        state.leaf_key = Some(leaf_key);
+        state.acceptors.clear();
+        drop(state);
+        Ok(())
+    }
+
+    /// Number of hostnames a certificate has been issued for
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.state.lock().unwrap().acceptors.len()
+    }
+
+    /// Returns an acceptor presenting a certificate for `host`, issuing one if needed
+    ///
+    /// The lock isn't held while issuing, so handshakes for other hosts aren't held up by it
+    pub(crate) fn acceptor(&self, host: &str) -> Result<TlsAcceptor, Box<dyn std::error::Error>> {
+        let state = self.state.lock().unwrap();
+        if let Some(acceptor) = state.acceptors.get(host) {
+            return Ok(acceptor.clone());
+        }
+        let leaf_key = state.leaf_key.clone();
+        drop(state);
+
+        info!("Issuing certificate for {}", host);
+        let encrypted = OpensslInterface::new().mk_ca_signed_cert(
+            host,
+            &self.ca,
+            PASSWORD,
+            leaf_key.as_deref(),
+        )?;
+        let identity = Identity::from_pkcs12(&encrypted, PASSWORD)?;
+        let acceptor = TlsAcceptor::new(identity)?;
+
+        let mut state = self.state.lock().unwrap();
+        if state.leaf_key != leaf_key {
+            // the key changed while issuing, so this certificate isn't worth keeping
+            return Ok(acceptor);
+        }
+        // another worker may have issued one for the same host meanwhile, which wins
+        let acceptor = state
+            .acceptors
+            .entry(host.to_string())
+            .or_insert(acceptor)
+            .clone();
+        drop(state);
+        Ok(acceptor)
+    }
+}
@@ -0,0 +1,260 @@
+use crate::{Error, Matcher, Mock, Response};
+use json::JsonValue;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use yaml_rust::{Yaml, YamlLoader};
+
+/// Keys a mock definition may have, so typos are reported instead of silently ignored
+const MOCK_KEYS: [&str; 8] = [
+    "method", "url", "headers", "query", "body", "response", "delay_ms", "expect",
+];
+const RESPONSE_KEYS: [&str; 4] = ["status", "headers", "body", "body_file"];
+
+/// Builds a [`Mock`] for every definition in the JSON or YAML file at `path`
+///
+/// Files ending in `.yaml` or `.yml` are read as YAML, any other as JSON
+pub(crate) fn load(path: &Path) -> Result<Vec<Mock>, Error> {
+    let invalid = |message: String| Error::InvalidFile {
+        path: path.to_path_buf(),
+        message,
+    };
+    let text = fs::read_to_string(path).map_err(|source| Error::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let document = if is_yaml(path) {
+        let mut documents =
+            YamlLoader::load_from_str(&text).map_err(|err| invalid(err.to_string()))?;
+        if documents.is_empty() {
+            JsonValue::new_array()
+        } else {
+            json_of(documents.swap_remove(0)).map_err(invalid)?
+        }
+    } else {
+        json::parse(&text).map_err(|err| invalid(err.to_string()))?
+    };
+
+    let definitions = if document.is_array() {
+        &document
+    } else {
+        &document["mocks"]
+    };
+    if !definitions.is_array() {
+        return Err(invalid(
+            "expected a list of mocks, or an object with a `mocks` list".to_string(),
+        ));
+    }
+
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    definitions
+        .members()
+        .enumerate()
+        .map(|(index, definition)| {
+            mock_of(definition, dir)
+                .map_err(|message| invalid(format!("mock {}: {}", index, message)))
+        })
+        .collect()
+}
+
+/// The JSON and YAML files in `dir`, sorted by name
+pub(crate) fn files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let io_error = |source| Error::Io {
+        path: dir.to_path_buf(),
+        source,
+    };
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).map_err(io_error)? {
+        let path = entry.map_err(io_error)?.path();
+        if path.is_file()
+            && (is_yaml(&path)
+                || path
+                    .extension()
+                    .is_some_and(|extension| extension == "json"))
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn is_yaml(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "yaml" || extension == "yml")
+}
+
+fn mock_of(definition: &JsonValue, dir: &Path) -> Result<Mock, String> {
+    check_keys(definition, &MOCK_KEYS, "the mock")?;
+    let method = definition["method"]
+        .as_str()
+        .ok_or("missing `method`")?
+        .to_ascii_uppercase();
+    let url = definition["url"].as_str().ok_or("missing `url`")?;
+    let mut mock = Mock::try_new(&method, url).map_err(|err| err.to_string())?;
+    for (name, matcher) in definition["headers"].entries() {
+        let matcher = matcher_of(matcher).map_err(|err| format!("header `{}`: {}", name, err))?;
+        mock.match_header(name, matcher);
+    }
+    for (name, matcher) in definition["query"].entries() {
+        let matcher = matcher_of(matcher).map_err(|err| format!("query `{}`: {}", name, err))?;
+        mock.match_query(name, matcher);
+    }
+    if !definition["body"].is_null() {
+        let matcher = matcher_of(&definition["body"]).map_err(|err| format!("body: {}", err))?;
+        mock.match_body(matcher);
+    }
+
+    if !definition["response"].is_null() {
+        mock.response = response_of(&definition["response"], dir)?;
+    }
+    let delay = &definition["delay_ms"];
+    if !delay.is_null() {
+        let delay = delay
+            .as_u64()
+            .ok_or("`delay_ms` should be a number of milliseconds")?;
+        mock.with_delay(Duration::from_millis(delay));
+    }
+
+    let expect = &definition["expect"];
+    if let Some(hits) = expect.as_usize() {
+        mock.expect(hits);
+    } else if expect.is_object() {
+        check_keys(expect, &["at_least", "at_most"], "`expect`")?;
+        let count = |key: &str| {
+            let value = &expect[key];
+            if value.is_null() {
+                Ok(None)
+            } else {
+                value
+                    .as_usize()
+                    .map(Some)
+                    .ok_or_else(|| format!("`expect.{}` should be a number", key))
+            }
+        };
+        if let Some(hits) = count("at_least")? {
+            mock.expect_at_least(hits);
+        }
+        if let Some(hits) = count("at_most")? {
+            mock.expect_at_most(hits);
+        }
+    } else if !expect.is_null() {
+        return Err(
+            "`expect` should be a number, or an object with `at_least` or `at_most`".into(),
+        );
+    }
+    Ok(mock)
+}
+
+fn response_of(definition: &JsonValue, dir: &Path) -> Result<Response, String> {
+    check_keys(definition, &RESPONSE_KEYS, "`response`")?;
+    let mut response = Response::new();
+    let status = &definition["status"];
+    if !status.is_null() {
+        let status = status
+            .as_u16()
+            .ok_or("`response.status` should be a number")?;
+        response
+            .try_with_status(status)
+            .map_err(|err| err.to_string())?;
+    }
+    for (name, value) in definition["headers"].entries() {
+        let value = value
+            .as_str()
+            .map(str::to_string)
+            .or_else(|| value.as_number().map(|number| number.to_string()))
+            .ok_or_else(|| format!("response header `{}` should be a string", name))?;
+        response.with_header(name, value);
+    }
+
+    let body = &definition["body"];
+    let body_file = &definition["body_file"];
+    if !body.is_null() && !body_file.is_null() {
+        return Err("`response.body` and `response.body_file` can't both be set".into());
+    }
+    if let Some(text) = body.as_str() {
+        response.with_body(text);
+    } else if !body.is_null() {
+        response.with_body_from_json(body.clone());
+    } else if let Some(file) = body_file.as_str() {
+        let path = dir.join(file);
+        let body =
+            fs::read(&path).map_err(|err| format!("body file {}: {}", path.display(), err))?;
+        response.with_body(body);
+    } else if !body_file.is_null() {
+        return Err("`response.body_file` should be a path".into());
+    }
+    Ok(response)
+}
+
+/// A string matches exactly, an object with a single key picks another kind of [`Matcher`]
+fn matcher_of(definition: &JsonValue) -> Result<Matcher, String> {
+    if let Some(text) = definition.as_str() {
+        return Ok(Matcher::Exact(text.to_string()));
+    }
+    let mut entries = definition.entries();
+    let (kind, value) = match (entries.next(), entries.next()) {
+        (Some(entry), None) => entry,
+        _ => return Err("expected a string, or an object with a single matcher".into()),
+    };
+    let text = || {
+        value
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| format!("`{}` should be a string", kind))
+    };
+    let matcher = match kind {
+        "exact" => Matcher::Exact(text()?),
+        "contains" => Matcher::Contains(text()?),
+        "regex" => Matcher::Regex(text()?),
+        "json" => Matcher::Json(value.clone()),
+        "partial_json" => Matcher::PartialJson(value.clone()),
+        "present" if value.as_bool() == Some(true) => Matcher::Present,
+        "absent" if value.as_bool() == Some(true) => Matcher::Absent,
+        "present" | "absent" => return Err(format!("`{}` should be true", kind)),
+        _ => return Err(format!("unknown matcher `{}`", kind)),
+    };
+    matcher.validate().map_err(|err| err.to_string())?;
+    Ok(matcher)
+}
+
+fn check_keys(definition: &JsonValue, known: &[&str], what: &str) -> Result<(), String> {
+    if !definition.is_object() {
+        return Err(format!("{} should be an object", what));
+    }
+    match definition.entries().find(|(key, _)| !known.contains(key)) {
+        Some((key, _)) => Err(format!("unknown key `{}` in {}", key, what)),
+        None => Ok(()),
+    }
+}
+
+/// Converts a YAML document to the equivalent JSON
+fn json_of(yaml: Yaml) -> Result<JsonValue, String> {
+    Ok(match yaml {
+        Yaml::Real(text) => text
+            .parse::<f64>()
+            .map_err(|_| format!("bad number `{}`", text))?
+            .into(),
+        Yaml::Integer(number) => number.into(),
+        Yaml::String(text) => text.into(),
+        Yaml::Boolean(value) => value.into(),
+        Yaml::Array(items) => {
+            JsonValue::Array(items.into_iter().map(json_of).collect::<Result<_, _>>()?)
+        }
+        Yaml::Hash(hash) => {
+            let mut object = JsonValue::new_object();
+            for (key, value) in hash {
+                let key = match key {
+                    Yaml::String(key) => key,
+                    Yaml::Integer(key) => key.to_string(),
+                    Yaml::Boolean(key) => key.to_string(),
+                    _ => return Err("mapping keys should be strings".to_string()),
+                };
+                object[key] = json_of(value)?;
+            }
+            object
+        }
+        Yaml::Null => JsonValue::Null,
+        Yaml::Alias(_) | Yaml::BadValue => return Err("aliases aren't supported".to_string()),
+    })
+}
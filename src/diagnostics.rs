@@ -0,0 +1,48 @@
+use crate::mock::Mismatch;
+use crate::{Mock, Request};
+
+/// How many of the closest mocks are listed for an unmatched request
+const CLOSEST_MOCKS: usize = 3;
+
+/// Explains why no mock matched `request`, listing the registered mocks that came closest
+pub(crate) fn near_misses(request: &Request, mocks: &[Mock]) -> String {
+    let mut candidates: Vec<(&Mock, Vec<Mismatch>)> = mocks
+        .iter()
+        .map(|mock| (mock, mock.mismatches(request)))
+        .collect();
+    // stable, so mocks registered first win ties
+    candidates.sort_by_key(|(_, mismatches)| {
+        mismatches
+            .iter()
+            .map(|mismatch| mismatch.weight)
+            .sum::<usize>()
+    });
+
+    let mut report = String::from("No matching response");
+    if candidates.is_empty() {
+        return report;
+    }
+
+    report.push_str("\n\nClosest mocks:");
+    for (mock, mismatches) in candidates.into_iter().take(CLOSEST_MOCKS) {
+        report.push_str(&format!(
+            "\n  {} ({} difference(s))",
+            mock,
+            mismatches.len()
+        ));
+        for mismatch in mismatches {
+            report.push_str(&format!("\n    - {}", mismatch.description));
+        }
+    }
+    report
+}
+
+/// One line summary of a request, in the same shape as a [`Mock`] is displayed
+pub(crate) fn describe(request: &Request) -> String {
+    format!(
+        "{} {}{}",
+        request.method(),
+        request.host().unwrap_or_default(),
+        request.path.as_deref().unwrap_or_default()
+    )
+}
@@ -0,0 +1,63 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Errors returned by the fallible parts of the [`Proxy`](crate::Proxy) API
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A certificate or key could not be generated, for the root CA or an intercepted host
+    Certificate(String),
+    /// [`Proxy::try_start`](crate::Proxy::try_start) was called on a running proxy
+    AlreadyStarted,
+    /// The server could not listen on the requested address
+    Bind {
+        /// Address the server tried to listen on, with port 0 meaning any free port
+        address: SocketAddr,
+        /// Why listening failed
+        source: std::io::Error,
+    },
+    /// A response status was not a valid HTTP status code
+    InvalidStatus(String),
+    /// A mock was given something that is neither a path nor a valid URL
+    InvalidUrl(String),
+    /// A file could not be read or written
+    Io {
+        /// The file or directory involved
+        path: PathBuf,
+        /// Why reading or writing failed
+        source: std::io::Error,
+    },
+    /// A file was read, but doesn't describe what it should
+    InvalidFile {
+        /// The file that was read
+        path: PathBuf,
+        /// What is wrong with it
+        message: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Certificate(err) => write!(f, "Failed to generate certificate: {}", err),
+            Self::AlreadyStarted => write!(f, "Tried to start an already started proxy"),
+            Self::Bind { address, source } => {
+                write!(f, "Failed to listen on {}: {}", address, source)
+            }
+            Self::InvalidStatus(err) => write!(f, "Bad status: {}", err),
+            Self::InvalidUrl(err) => write!(f, "Bad url: {}", err),
+            Self::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Self::InvalidFile { path, message } => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Bind { source, .. } | Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// A network failure a [`Mock`](crate::Mock) simulates instead of responding normally
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Fault {
+    /// Resets the connection without sending anything
+    ConnectionReset,
+    /// Closes the connection without sending anything
+    EmptyResponse,
+    /// Sends the head as usual, but closes the connection after this many bytes of the body
+    TruncateBodyAfter(usize),
+    /// Sends random bytes that aren't a valid response, then closes the connection
+    GarbageBytes,
+    /// Accepts the `CONNECT` of a client, then closes the tunnel before the TLS handshake
+    ///
+    /// Only the host of the [`Mock`](crate::Mock) is matched, as the request inside the tunnel
+    /// is never sent, so the mock needs a host and must be the first registered for it. Plain
+    /// HTTP requests get an [`Fault::EmptyResponse`] instead
+    CloseBeforeTlsHandshake,
+    /// Never responds, keeping the connection open until the client gives up or the proxy is
+    /// stopped
+    HangForever,
+}
+
+/// Number of random bytes sent by [`Fault::GarbageBytes`]
+const GARBAGE_LENGTH: usize = 64;
+
+pub(crate) fn garbage() -> Vec<u8> {
+    (0..GARBAGE_LENGTH).map(|_| rand::random()).collect()
+}
+
+/// Finishes off a connection whose response was cut short by `fault`
+pub(crate) fn finish(stream: &mut TcpStream, fault: Fault) -> io::Result<()> {
+    match fault {
+        Fault::ConnectionReset => {
+            // closing with a zero linger timeout sends a RST instead of a FIN
+            socket2::SockRef::from(&*stream).set_linger(Some(Duration::ZERO))
+        }
+        Fault::HangForever => {
+            // reading until the client or `Proxy::stop` closes the connection
+            let mut buf = [0; 1024];
+            loop {
+                match stream.read(&mut buf) {
+                    Ok(0) => return Ok(()),
+                    Ok(_) => {}
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        _ => stream.flush(),
+    }
+}
+
+/// Discards everything written past a given number of bytes
+pub(crate) struct Truncated<'a> {
+    pub(crate) inner: &'a mut dyn Write,
+    pub(crate) remaining: usize,
+}
+
+impl Write for Truncated<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = buf.len().min(self.remaining);
+        if length > 0 {
+            self.inner.write_all(&buf[..length])?;
+            self.remaining -= length;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,174 @@
+use crate::{Error, Exhausted, Mock, RecordedRequest, Response};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use json::JsonValue;
+use std::fs;
+use std::path::Path;
+
+/// Response headers describing the body as it was sent, not as it was saved in the archive
+const SKIPPED_HEADERS: [&str; 4] = [
+    "content-length",
+    "content-encoding",
+    "transfer-encoding",
+    "connection",
+];
+
+/// Builds a [`Mock`] for every entry of the archive at `path`
+///
+/// Entries for the same method and URL respond in turn, the last one repeating
+pub(crate) fn load(path: &Path) -> Result<Vec<Mock>, Error> {
+    let invalid = |message: String| Error::InvalidFile {
+        path: path.to_path_buf(),
+        message,
+    };
+    let text = fs::read_to_string(path).map_err(|source| Error::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let har = json::parse(&text).map_err(|err| invalid(err.to_string()))?;
+    let entries = &har["log"]["entries"];
+    if !entries.is_array() {
+        return Err(invalid("missing log.entries".to_string()));
+    }
+
+    let mut mocks: Vec<(String, String, Mock)> = Vec::new();
+    for (index, entry) in entries.members().enumerate() {
+        let invalid = |message: &str| invalid(format!("entry {}: {}", index, message));
+        let method = entry["request"]["method"]
+            .as_str()
+            .ok_or_else(|| invalid("missing request.method"))?;
+        let url = entry["request"]["url"]
+            .as_str()
+            .ok_or_else(|| invalid("missing request.url"))?;
+        let mut mock = Mock::try_new(method, url).map_err(|err| invalid(&err.to_string()))?;
+        let response = response_of(&entry["response"]).map_err(|message| invalid(&message))?;
+
+        // an earlier entry for the same request gives way to this one after a single hit
+        if let Some((_, _, earlier)) = mocks
+            .iter_mut()
+            .rev()
+            .find(|(m, u, _)| m == method && u == url)
+        {
+            earlier.when_sequence_exhausted(Exhausted::FallThrough);
+        }
+        mock.with_response_sequence(vec![response]);
+        mocks.push((method.to_string(), url.to_string(), mock));
+    }
+    Ok(mocks.into_iter().map(|(_, _, mock)| mock).collect())
+}
+
+fn response_of(response: &JsonValue) -> Result<Response, String> {
+    let mut built = Response::new();
+    let status = response["status"]
+        .as_u16()
+        .ok_or("missing response.status")?;
+    built
+        .try_with_status(status)
+        .map_err(|err| err.to_string())?;
+
+    for header in response["headers"].members() {
+        let (name, value) = match (header["name"].as_str(), header["value"].as_str()) {
+            (Some(name), Some(value)) => (name, value),
+            _ => return Err("headers should have a name and value".to_string()),
+        };
+        if !SKIPPED_HEADERS.iter().any(|h| name.eq_ignore_ascii_case(h)) {
+            built.with_header(name, value);
+        }
+    }
+
+    let content = &response["content"];
+    if let Some(text) = content["text"].as_str() {
+        if content["encoding"].as_str() == Some("base64") {
+            let body = BASE64.decode(text).map_err(|err| err.to_string())?;
+            built.with_body(body);
+        } else {
+            built.with_body(text);
+        }
+    }
+    Ok(built)
+}
+
+/// Writes every recorded request, with the response it got, to an archive at `path`
+pub(crate) fn export(entries: &[RecordedRequest], path: &Path) -> Result<(), Error> {
+    let entries: Vec<JsonValue> = entries.iter().map(entry_of).collect();
+    let har = json::object! {
+        log: {
+            version: "1.2",
+            creator: { name: env!("CARGO_PKG_NAME"), version: env!("CARGO_PKG_VERSION") },
+            entries: entries,
+        },
+    };
+    fs::write(path, json::stringify_pretty(har, 2)).map_err(|source| Error::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn entry_of(entry: &RecordedRequest) -> JsonValue {
+    let request = entry.request();
+    let response = entry.response();
+    let version = format!("HTTP/{}.{}", request.version.0, request.version.1);
+    let body = response.body_bytes();
+
+    let mut har_request = json::object! {
+        method: request.method(),
+        url: request.url(entry.scheme()),
+        httpVersion: version.as_str(),
+        cookies: [],
+        headers: headers_of(request.headers()),
+        queryString: request
+            .query_pairs()
+            .into_iter()
+            .map(|(name, value)| json::object! { name: name, value: value })
+            .collect::<Vec<_>>(),
+        headersSize: -1,
+        bodySize: request.body().len(),
+    };
+    if !request.body().is_empty() {
+        let mut post_data = content_of(request.body());
+        post_data["mimeType"] = request.header("content-type").unwrap_or_default().into();
+        har_request["postData"] = post_data;
+    }
+
+    let mut content = content_of(&body);
+    content["size"] = body.len().into();
+    content["mimeType"] = response
+        .header_values("content-type")
+        .next()
+        .unwrap_or_default()
+        .into();
+
+    json::object! {
+        startedDateTime: entry.timestamp().to_rfc3339(),
+        time: 0,
+        request: har_request,
+        response: {
+            status: response.status.as_u16(),
+            statusText: response.status.canonical_reason().unwrap_or_default(),
+            httpVersion: version.as_str(),
+            cookies: [],
+            headers: headers_of(&response.headers),
+            content: content,
+            redirectURL: response.header_values("location").next().unwrap_or_default(),
+            headersSize: -1,
+            bodySize: body.len(),
+        },
+        cache: {},
+        timings: { send: 0, wait: 0, receive: 0 },
+    }
+}
+
+fn headers_of(headers: &[(String, String)]) -> Vec<JsonValue> {
+    headers
+        .iter()
+        .map(|(name, value)| json::object! { name: name.as_str(), value: value.as_str() })
+        .collect()
+}
+
+/// The body as text, base64 encoded unless it is valid UTF-8
+fn content_of(body: &[u8]) -> JsonValue {
+    std::str::from_utf8(body).map_or_else(
+        |_| json::object! { text: BASE64.encode(body), encoding: "base64" },
+        |text| json::object! { text: text },
+    )
+}
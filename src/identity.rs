@@ -76,14 +76,25 @@ fn mk_request(key_pair: &PKey<Private>) -> Result<X509Req, ErrorStack> {
     Ok(req)
 }
 
+/// Make a private key for a leaf certificate
+pub fn mk_leaf_key() -> Result<PKey<Private>, ErrorStack> {
+    let rsa = Rsa::generate(2048)?;
+    PKey::from_rsa(rsa)
+}
+
 /// Make a certificate and private key signed by the given CA cert and private key
+///
+/// Uses the given `leaf_key` if any, otherwise generates a new one
 pub fn mk_ca_signed_cert(
     domain: &str,
     ca_cert: &X509Ref,
     ca_key_pair: &PKeyRef<Private>,
+    leaf_key: Option<PKey<Private>>,
 ) -> Result<(X509, PKey<Private>), ErrorStack> {
-    let rsa = Rsa::generate(2048)?;
-    let key_pair = PKey::from_rsa(rsa)?;
+    let key_pair = match leaf_key {
+        Some(key_pair) => key_pair,
+        None => mk_leaf_key()?,
+    };
 
     let req = mk_request(&key_pair)?;
 
@@ -142,24 +153,33 @@ impl OpensslInterface {
     }
 }
 impl IdentityInterface for OpensslInterface {
-    fn mk_ca_cert(&self) -> Result<Cert, std::boxed::Box<(dyn std::error::Error + 'static)>> {
+    fn mk_ca_cert(&self) -> Result<Cert, std::boxed::Box<dyn std::error::Error + 'static>> {
         let (cert, key) = mk_ca_cert()?;
 
         Ok(Cert::new(cert.to_pem()?, key.private_key_to_pem_pkcs8()?))
     }
+    fn mk_leaf_key(&self) -> Result<Vec<u8>, std::boxed::Box<dyn std::error::Error + 'static>> {
+        Ok(mk_leaf_key()?.private_key_to_pem_pkcs8()?)
+    }
     fn mk_ca_signed_cert(
         &self,
         cn: &str,
         ca_cert_pair: &Cert,
         password: &str,
-    ) -> Result<Vec<u8>, std::boxed::Box<(dyn std::error::Error + 'static)>> {
+        leaf_key: Option<&[u8]>,
+    ) -> Result<Vec<u8>, std::boxed::Box<dyn std::error::Error + 'static>> {
         let ca_cert = openssl::x509::X509::from_pem(&ca_cert_pair.cert);
         let ca_pkey = PKey::private_key_from_pem(&ca_cert_pair.pkey)?;
+        let leaf_key = leaf_key.map(PKey::private_key_from_pem).transpose()?;
 
-        let (cert, key) = mk_ca_signed_cert(cn, ca_cert.as_ref().unwrap(), ca_pkey.as_ref())?;
+        let (cert, key) =
+            mk_ca_signed_cert(cn, ca_cert.as_ref().unwrap(), ca_pkey.as_ref(), leaf_key)?;
 
         Ok(openssl::pkcs12::Pkcs12::builder()
-            .build(password, cn, &key, &cert)?
+            .name(cn)
+            .pkey(&key)
+            .cert(&cert)
+            .build2(password)?
             .to_der()?)
     }
 }
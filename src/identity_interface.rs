@@ -4,7 +4,7 @@ pub(super) struct Cert {
     pub(super) pkey: Vec<u8>,
 }
 impl Cert {
-    pub(super) fn new(cert: Vec<u8>, pkey: Vec<u8>) -> Self {
+    pub(super) const fn new(cert: Vec<u8>, pkey: Vec<u8>) -> Self {
         Self { cert, pkey }
     }
     pub(super) fn cert(&self) -> Vec<u8> {
@@ -14,10 +14,14 @@ impl Cert {
 
 pub(super) trait IdentityInterface {
     fn mk_ca_cert(&self) -> Result<Cert, Box<dyn std::error::Error>>;
+    /// Makes a PEM encoded private key that can be shared between leaf certificates
+    fn mk_leaf_key(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>>;
+    /// Makes a PKCS#12 bundle for `domain`, using `leaf_key` if given
     fn mk_ca_signed_cert(
         &self,
         domain: &str,
         ca_cert: &Cert,
         password: &str,
+        leaf_key: Option<&[u8]>,
     ) -> Result<Vec<u8>, Box<dyn std::error::Error>>;
 }
@@ -1,4 +1,4 @@
-use crate::Cert;
+use crate::identity_interface::Cert;
 use crate::IdentityInterface;
 use chrono::Local;
 use rcgen::{
@@ -13,9 +13,9 @@ pub(super) fn mk_ca_cert() -> Result<Certificate, Box<dyn std::error::Error>> {
     params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
     params.name_constraints = Some(rcgen::NameConstraints {
         excluded_subtrees: vec![],
-        permitted_subtrees: vec![rcgen::GeneralSubtree::DirectoryName {
-            0: rcgen::DistinguishedName::new(),
-        }],
+        permitted_subtrees: vec![rcgen::GeneralSubtree::DirectoryName(
+            rcgen::DistinguishedName::new(),
+        )],
     });
 
     Certificate::from_params(params).map_err(|f| f.into())
@@ -26,8 +26,10 @@ pub fn mk_ca_signed_cert(
     domain: &str,
     ca_cert: &Certificate,
     password: &str,
+    leaf_key: Option<KeyPair>,
 ) -> Result<Vec<u8>, RcgenError> {
     let mut params = CertificateParams::new(vec![domain.to_owned()]);
+    params.key_pair = leaf_key;
 
     params.serial_number = Some(rand::random());
     params.not_before = Local::now().into();
@@ -66,7 +68,7 @@ struct RingInterface {}
 impl IdentityInterface for RingInterface {
     fn mk_ca_cert(
         &self,
-    ) -> std::result::Result<Cert, std::boxed::Box<(dyn std::error::Error + 'static)>> {
+    ) -> std::result::Result<Cert, std::boxed::Box<dyn std::error::Error + 'static>> {
         let cert = mk_ca_cert()?;
 
         Ok(Cert::new(
@@ -74,17 +76,29 @@ impl IdentityInterface for RingInterface {
             cert.serialize_private_key_pem().as_bytes().to_vec(),
         ))
     }
+    fn mk_leaf_key(
+        &self,
+    ) -> std::result::Result<std::vec::Vec<u8>, std::boxed::Box<dyn std::error::Error + 'static>>
+    {
+        let keypair = KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)?;
+
+        Ok(keypair.serialize_pem().as_bytes().to_vec())
+    }
     fn mk_ca_signed_cert(
         &self,
         domain: &str,
         ca_cert: &Cert,
         password: &str,
-    ) -> std::result::Result<std::vec::Vec<u8>, std::boxed::Box<(dyn std::error::Error + 'static)>>
+        leaf_key: Option<&[u8]>,
+    ) -> std::result::Result<std::vec::Vec<u8>, std::boxed::Box<dyn std::error::Error + 'static>>
     {
         let keypair = KeyPair::from_pem(&ca_cert.pkey())?;
         let params = CertificateParams::from_ca_cert_pem(&ca_cert.cert_string(), keypair)?;
         let certificate = Certificate::from_params(params)?;
+        let leaf_key = leaf_key
+            .map(|pem| KeyPair::from_pem(&to_string(pem)))
+            .transpose()?;
 
-        mk_ca_signed_cert(domain, &certificate, password).map_err(|f| f.into())
+        mk_ca_signed_cert(domain, &certificate, password, leaf_key).map_err(|f| f.into())
     }
 }
@@ -0,0 +1,103 @@
+use crate::{Request, Response};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use std::sync::{Arc, Mutex};
+
+/// How a request reached the [`Proxy`](crate::Proxy)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    /// Sent in plain text, straight to the proxy
+    Http,
+    /// Tunnelled through `CONNECT`, then decrypted by the proxy
+    Https,
+}
+
+/// A [`Request`] received by the [`Proxy`](crate::Proxy), along with how it was answered
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    request: Request,
+    timestamp: DateTime<Utc>,
+    scheme: Scheme,
+    mock_id: Option<usize>,
+    passed_through: bool,
+    response: Response,
+}
+
+impl RecordedRequest {
+    pub(crate) fn new(
+        request: Request,
+        scheme: Scheme,
+        mock_id: Option<usize>,
+        response: Response,
+    ) -> Self {
+        Self {
+            request,
+            timestamp: Utc::now(),
+            scheme,
+            mock_id,
+            passed_through: false,
+            response,
+        }
+    }
+
+    pub(crate) const fn passed_through(mut self) -> Self {
+        self.passed_through = true;
+        self
+    }
+
+    /// The request as it was received
+    pub const fn request(&self) -> &Request {
+        &self.request
+    }
+
+    /// When the request was received
+    pub const fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    /// Whether the request was tunnelled or sent in plain text
+    pub const fn scheme(&self) -> Scheme {
+        self.scheme
+    }
+
+    /// The [`MockHandle::id`](crate::MockHandle::id) of the mock that answered, if any did
+    pub const fn mock_id(&self) -> Option<usize> {
+        self.mock_id
+    }
+
+    /// Whether the request matched no mock and was forwarded upstream, see
+    /// [`ProxyBuilder::passthrough`](crate::ProxyBuilder::passthrough)
+    pub const fn is_passed_through(&self) -> bool {
+        self.passed_through
+    }
+
+    /// The status the proxy responded with
+    pub const fn status(&self) -> StatusCode {
+        self.response.status
+    }
+
+    /// The response the proxy sent
+    pub(crate) const fn response(&self) -> &Response {
+        &self.response
+    }
+}
+
+/// Every request received, in order, shared between the proxy and its connections
+#[derive(Clone, Default)]
+pub(crate) struct Journal {
+    entries: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl Journal {
+    pub(crate) fn record(&self, entry: RecordedRequest) {
+        self.entries.lock().unwrap().push(entry);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    pub(crate) fn entries(&self) -> Vec<RecordedRequest> {
+        self.entries.lock().unwrap().clone()
+    }
+}
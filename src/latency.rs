@@ -0,0 +1,85 @@
+use rand::Rng;
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How slowly a [`Response`](crate::Response) is sent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Latency {
+    /// Shortest and longest wait before the first byte is sent
+    pub(crate) delay: Option<(Duration, Duration)>,
+    /// How long sending the whole body should take
+    pub(crate) body_duration: Option<Duration>,
+    pub(crate) bytes_per_second: Option<u64>,
+}
+
+impl Latency {
+    /// Picks the wait before the first byte, somewhere within the configured range
+    pub(crate) fn first_byte_delay(&self) -> Option<Duration> {
+        self.delay.map(|(min, max)| {
+            if min >= max {
+                min
+            } else {
+                rand::thread_rng().gen_range(min..=max)
+            }
+        })
+    }
+
+    /// Bytes per second to send a body of `length` bytes at, if it should be slowed down
+    ///
+    /// The slower of the throttle and the body duration wins
+    pub(crate) fn rate(&self, length: Option<usize>) -> Option<f64> {
+        let throttle = self
+            .bytes_per_second
+            .filter(|rate| *rate > 0)
+            .map(|rate| rate as f64);
+        let spread = self
+            .body_duration
+            .zip(length)
+            .filter(|(duration, length)| !duration.is_zero() && *length > 0)
+            .map(|(duration, length)| length as f64 / duration.as_secs_f64());
+        match (throttle, spread) {
+            (Some(throttle), Some(spread)) => Some(throttle.min(spread)),
+            (rate, None) | (None, rate) => rate,
+        }
+    }
+}
+
+/// Writes no faster than a given number of bytes per second
+pub(crate) struct Paced<'a> {
+    inner: &'a mut dyn Write,
+    bytes_per_second: f64,
+    started: Instant,
+    written: u64,
+}
+
+impl<'a> Paced<'a> {
+    pub(crate) fn new(inner: &'a mut dyn Write, bytes_per_second: f64) -> Self {
+        Self {
+            inner,
+            bytes_per_second,
+            started: Instant::now(),
+            written: 0,
+        }
+    }
+}
+
+impl Write for Paced<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // small enough slices that the client sees a steady trickle
+        let slice = ((self.bytes_per_second / 20.0) as usize).max(1);
+        let written = self.inner.write(&buf[..buf.len().min(slice)])?;
+        self.inner.flush()?;
+        self.written += written as u64;
+
+        let due = Duration::from_secs_f64(self.written as f64 / self.bytes_per_second);
+        if let Some(wait) = due.checked_sub(self.started.elapsed()) {
+            thread::sleep(wait);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
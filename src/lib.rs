@@ -1,6 +1,7 @@
 #![deny(missing_docs)]
 #![deny(clippy::redundant_clone)]
 #![deny(clippy::nursery)]
+#![allow(clippy::redundant_pub_crate)]
 
 //! This library was built to help test systems that use libraries which don't provide any
 //! testing utilities themselves. It works by overriding the proxy and root ca attributes
@@ -8,274 +9,748 @@
 //!
 //! The following shows how to setup reqwest to send requests to a [`Proxy`] instance: [simple_test](https://github.com/Mause/mock_proxy/blob/main/src/test.rs)
 
+use crate::builder::PortChoice;
+use crate::certificates::Certificates;
+use crate::fault::Truncated;
 use crate::identity::OpensslInterface;
-use crate::identity_interface::{Cert, IdentityInterface};
-use crate::mock::{split_url, Response};
+use crate::identity_interface::IdentityInterface;
+use crate::journal::Journal;
+use crate::latency::Paced;
+use crate::pool::ThreadPool;
+use crate::recording::Recorder;
+use crate::request::Limits;
+use crate::streaming::StreamedBody;
 use log::{error, info};
 use native_tls::TlsStream;
+use std::collections::HashMap;
 use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::sync::mpsc;
-use std::thread;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
+mod builder;
+mod certificates;
+mod definitions;
+mod diagnostics;
+mod error;
+mod fault;
+mod har;
 mod identity;
 mod identity_interface;
+#[allow(dead_code)]
 mod identity_ring;
+mod journal;
+mod latency;
+mod matcher;
 mod mock;
+mod openapi;
+mod passthrough;
+mod pool;
+mod recording;
+mod request;
+mod streaming;
 #[cfg(test)]
 mod test;
-pub use crate::mock::Mock;
+mod wiremock;
+pub use crate::builder::ProxyBuilder;
+pub use crate::error::Error;
+pub use crate::fault::Fault;
+pub use crate::journal::{RecordedRequest, Scheme};
+pub use crate::matcher::Matcher;
+pub use crate::mock::{Exhausted, Mock, MockHandle, Response};
+pub use crate::passthrough::Passthrough;
+pub use crate::request::Request;
 
-const SERVER_ADDRESS_INTERNAL: &str = "127.0.0.1:1234";
+const DEFAULT_PORT: u16 = 1234;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_WORKER_THREADS: usize = 8;
+const DEFAULT_UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// Primary interface for the library
 pub struct Proxy {
-    mocks: Vec<Mock>,
-    listening_addr: Option<SocketAddr>,
-    started: bool,
-    cert: Cert,
+    /// Shared with the server, so mocks can be changed while it is running
+    mocks: Arc<RwLock<Vec<Mock>>>,
+    handles: Vec<MockHandle>,
+    next_mock_id: usize,
+    server: Option<Server>,
+    certificates: Arc<Certificates>,
+    journal: Journal,
+    config: Config,
 }
 
-impl Default for Proxy {
+/// Everything a connection handler needs from the [`Proxy`]
+struct Shared {
+    certificates: Arc<Certificates>,
+    mocks: Arc<RwLock<Vec<Mock>>>,
+    journal: Journal,
+    limits: Limits,
+    default_delay: Duration,
+    passthrough: Passthrough,
+    upstream: Option<SocketAddr>,
+    upstream_timeout: Duration,
+    recorder: Option<Recorder>,
+}
+
+/// Settings shared with the server thread when the proxy is started
+#[derive(Debug, Clone)]
+struct Config {
+    bind_address: IpAddr,
+    port: PortChoice,
+    idle_timeout: Duration,
+    worker_threads: usize,
+    limits: Limits,
+    default_delay: Duration,
+    passthrough: Passthrough,
+    upstream: Option<SocketAddr>,
+    upstream_timeout: Duration,
+    recording: Option<PathBuf>,
+    fail_verify_on_unmatched: bool,
+}
+
+impl Default for Config {
     fn default() -> Self {
-        let cert = OpensslInterface::new()
-            .mk_ca_cert()
-            .expect("Failed to generate CA certificate");
         Self {
-            mocks: Vec::new(),
-            listening_addr: None,
-            started: false,
-            cert,
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: PortChoice::Preferred(DEFAULT_PORT),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            worker_threads: DEFAULT_WORKER_THREADS,
+            limits: Limits::default(),
+            default_delay: Duration::ZERO,
+            passthrough: Passthrough::None,
+            upstream: None,
+            upstream_timeout: DEFAULT_UPSTREAM_TIMEOUT,
+            recording: None,
+            fail_verify_on_unmatched: false,
         }
     }
 }
 
+/// A running accept loop, owned by the [`Proxy`] that started it
+struct Server {
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    connections: Connections,
+    handle: JoinHandle<()>,
+}
+
+/// Connections currently open against the server, so they can be closed on shutdown
+#[derive(Clone, Default)]
+struct Connections {
+    state: Arc<Mutex<OpenStreams>>,
+}
+
+#[derive(Default)]
+struct OpenStreams {
+    closing: bool,
+    streams: HashMap<usize, TcpStream>,
+}
+
+impl Connections {
+    fn insert(&self, id: usize, stream: &TcpStream) {
+        let mut state = self.state.lock().unwrap();
+        if state.closing {
+            // accepted while shutting down, so it won't be seen by `close_all`
+            let _ = stream.shutdown(Shutdown::Read);
+        } else if let Ok(stream) = stream.try_clone() {
+            state.streams.insert(id, stream);
+        }
+    }
+
+    /// Removes the connection once the returned guard is dropped, even if its handler panics
+    fn release_on_drop(&self, id: usize) -> Release {
+        Release {
+            connections: self.clone(),
+            id,
+        }
+    }
+
+    /// Stops reading from every open connection, letting responses in flight finish
+    fn close_all(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closing = true;
+        for stream in state.streams.values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    }
+}
+
+/// Drops the clone of a finished connection kept by [`Connections`], closing its socket
+struct Release {
+    connections: Connections,
+    id: usize,
+}
+
+impl Drop for Release {
+    fn drop(&mut self) {
+        let mut state = self
+            .connections
+            .state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        state.streams.remove(&self.id);
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Self::try_new().unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
 impl Proxy {
     /// Builds a [`Default`] instance
+    ///
+    /// # Panics
+    /// If the root CA certificate can't be generated, see [`Proxy::try_new`]
     pub fn new() -> Self {
         Self::default()
     }
 
+    /// Builds a [`Default`] instance, without panicking
+    ///
+    /// # Errors
+    /// If the root CA certificate can't be generated
+    pub fn try_new() -> Result<Self, Error> {
+        let cert = OpensslInterface::new()
+            .mk_ca_cert()
+            .map_err(|err| Error::Certificate(err.to_string()))?;
+        Ok(Self {
+            mocks: Arc::default(),
+            handles: Vec::new(),
+            next_mock_id: 0,
+            server: None,
+            certificates: Arc::new(Certificates::new(cert)),
+            journal: Journal::default(),
+            config: Config::default(),
+        })
+    }
+
+    /// Configure where the proxy listens before creating it
+    pub fn builder() -> ProxyBuilder {
+        ProxyBuilder::new()
+    }
+
     /// Register a given mock with the proxy
     ///
-    /// # Panics
-    /// Will panic if proxy has already been started
-    pub fn register(&mut self, mock: Mock) {
-        if self.started {
-            panic!("Cannot add mocks to a started proxy");
+    /// Mocks are tried in the order they were registered. Can be called while the proxy is
+    /// running. The returned [`MockHandle`] reports how often the mock was hit
+    pub fn register(&mut self, mock: Mock) -> MockHandle {
+        let (mock, handle) = self.prepare(mock);
+        self.mocks.write().unwrap().push(mock);
+        self.handles.push(handle.clone());
+        handle
+    }
+
+    /// Swaps the mock behind `handle` for another, which takes its place in the order
+    ///
+    /// Registers `mock` as usual if `handle` was already removed
+    pub fn replace(&mut self, handle: &MockHandle, mock: Mock) -> MockHandle {
+        let (mock, replacement) = self.prepare(mock);
+
+        let mut mocks = self.mocks.write().unwrap();
+        match mocks.iter().position(|m| m.id == handle.id()) {
+            Some(index) => mocks[index] = mock,
+            None => mocks.push(mock),
         }
-        self.mocks.push(mock);
+        drop(mocks);
+
+        self.handles.retain(|h| h.id() != handle.id());
+        self.handles.push(replacement.clone());
+        replacement
     }
 
-    /// Start the proxy server
+    /// Unregisters the mock behind `handle`, returning whether it was still registered
+    pub fn remove(&mut self, handle: &MockHandle) -> bool {
+        self.handles.retain(|h| h.id() != handle.id());
+
+        let mut mocks = self.mocks.write().unwrap();
+        let before = mocks.len();
+        mocks.retain(|m| m.id != handle.id());
+        before != mocks.len()
+    }
+
+    /// Unregisters every mock and forgets every request received so far
     ///
-    /// # Panics
-    /// Will panic if proxy has already been started
-    pub fn start(&mut self) {
-        start_proxy(self);
+    /// Leaves the proxy running, ready for the next phase of a test
+    pub fn reset(&mut self) {
+        self.mocks.write().unwrap().clear();
+        self.handles.clear();
+        self.journal.clear();
     }
 
-    /// Start the server
-    /// # Panics
-    /// Not supported yet
-    pub fn stop(&mut self) {
-        todo!();
+    fn prepare(&mut self, mut mock: Mock) -> (Mock, MockHandle) {
+        let handle = mock.register(self.next_mock_id);
+        self.next_mock_id += 1;
+        (mock, handle)
     }
 
-    /// Address and port of the local server.
-    /// Can be used with [`std::net::TcpStream`].
+    /// Makes [`Proxy::verify`] also fail if any request went unmatched
+    pub const fn fail_verify_on_unmatched(&mut self) -> &mut Self {
+        self.config.fail_verify_on_unmatched = true;
+        self
+    }
+
+    /// Checks every registered mock was hit as many times as expected
     ///
     /// # Panics
-    /// If server is not running
-    pub fn address(&self) -> SocketAddr {
-        self.listening_addr.expect("server should be listening")
+    /// Listing every unmet expectation, if there are any, along with every unmatched request
+    /// if [`Proxy::fail_verify_on_unmatched`] was called
+    pub fn verify(&self) {
+        let mut failures = Vec::new();
+
+        let unsatisfied: Vec<String> = self
+            .handles
+            .iter()
+            .filter_map(MockHandle::unsatisfied)
+            .collect();
+        if !unsatisfied.is_empty() {
+            failures.push(format!(
+                "{} mock expectation(s) were not met:\n  {}",
+                unsatisfied.len(),
+                unsatisfied.join("\n  ")
+            ));
+        }
+
+        let unmatched = self.unmatched_requests();
+        if self.config.fail_verify_on_unmatched && !unmatched.is_empty() {
+            let mocks = self.mocks.read().unwrap();
+            let requests: Vec<String> = unmatched
+                .iter()
+                .map(|entry| {
+                    let request = entry.request();
+                    let report = diagnostics::near_misses(request, &mocks);
+                    format!("{}: {}", diagnostics::describe(request), report)
+                        .replace('\n', "\n    ")
+                })
+                .collect();
+            drop(mocks);
+            failures.push(format!(
+                "{} request(s) did not match any mock:\n  {}",
+                requests.len(),
+                requests.join("\n  ")
+            ));
+        }
+
+        if !failures.is_empty() {
+            panic!("{}", failures.join("\n"));
+        }
     }
 
-    /// A local `http://…` URL of the server.
+    /// Registers a mock for every request recorded with [`ProxyBuilder::record_to`] in `dir`
     ///
-    /// # Panics
-    /// If server is not running
-    pub fn url(&self) -> String {
-        format!("http://{}", self.address())
+    /// A request recorded several times replays its responses in the order they were
+    /// recorded, then repeats the last one. Requests with a body only match the same body
+    ///
+    /// # Errors
+    /// If the fixtures can't be read, or aren't valid
+    pub fn replay_from<P: AsRef<Path>>(&mut self, dir: P) -> Result<Vec<MockHandle>, Error> {
+        let mocks = recording::load(dir.as_ref())?;
+        Ok(mocks.into_iter().map(|mock| self.register(mock)).collect())
     }
 
-    /// Returns the root CA certificate of the server
+    /// Registers a mock for every entry of the HTTP Archive (HAR) at `path`, such as one saved
+    /// from the network tab of a browser
     ///
-    /// # Panics
-    /// If PEM conversion fails
-    pub fn get_certificate(&self) -> Vec<u8> {
-        self.cert.cert()
+    /// Entries for the same method and URL respond in the order they were captured, then the
+    /// last one repeats. Base64 encoded bodies are decoded
+    ///
+    /// # Errors
+    /// If the archive can't be read, or isn't valid
+    pub fn load_har<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<MockHandle>, Error> {
+        let mocks = har::load(path.as_ref())?;
+        Ok(mocks.into_iter().map(|mock| self.register(mock)).collect())
     }
-}
 
-#[derive(Debug, Clone)]
-struct Request {
-    error: Option<String>,
-    host: Option<String>,
-    path: Option<String>,
-    method: Option<String>,
-    version: (u8, u8),
-}
+    /// Writes every request received so far, and how it was answered, to an HTTP Archive
+    /// (HAR) at `path`
+    ///
+    /// Bodies that aren't valid UTF-8 are base64 encoded
+    ///
+    /// # Errors
+    /// If the archive can't be written
+    pub fn export_har<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        har::export(&self.received_requests(), path.as_ref())
+    }
 
-impl std::fmt::Display for Request {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        f.debug_struct("Request")
-            .field("method", &self.method)
-            .field("host", &self.host)
-            .field("path", &self.path)
-            .finish()
+    /// Registers a mock for every definition in the JSON or YAML file at `path`
+    ///
+    /// Files ending in `.yaml` or `.yml` are read as YAML, any other as JSON. The file holds a
+    /// list of mocks, or an object with a `mocks` list:
+    ///
+    /// ```yaml
+    /// mocks:
+    ///   - method: POST                      # required
+    ///     url: https://api.example.com/users # required, the host is optional
+    ///     headers:                          # optional, each must match
+    ///       Authorization: Bearer token     # a string matches exactly
+    ///       X-Trace: { present: true }
+    ///     query:                            # optional, other parameters may be sent too
+    ///       page: { regex: "^[0-9]+$" }
+    ///     body: { partial_json: { name: alice } } # optional
+    ///     response:                         # optional, defaults to an empty 200
+    ///       status: 201
+    ///       headers: { Content-Type: application/json }
+    ///       body: { id: 1 }                 # a string is sent as is, anything else as JSON
+    ///       # body_file: user.json          # or read from a file next to this one
+    ///     delay_ms: 100                     # optional
+    ///     expect: 1                         # optional, or { at_least: 1, at_most: 3 }
+    /// ```
+    ///
+    /// Matchers are a string, or an object with one of `exact`, `contains`, `regex`, `json`,
+    /// `partial_json`, `present: true` or `absent: true`. See [`Matcher`]
+    ///
+    /// # Errors
+    /// If the file can't be read, or any definition in it is invalid, in which case nothing is
+    /// registered and the error names the offending mock by its index
+    pub fn load_mocks_from_file<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<Vec<MockHandle>, Error> {
+        let mocks = definitions::load(path.as_ref())?;
+        Ok(mocks.into_iter().map(|mock| self.register(mock)).collect())
     }
-}
-impl Request {
-    const fn is_ok(&self) -> bool {
-        self.error().is_none()
+
+    /// Registers the mocks of every `.json`, `.yaml` and `.yml` file in `dir`, in file name
+    /// order, as [`Proxy::load_mocks_from_file`] would
+    ///
+    /// # Errors
+    /// If the directory or any of its files can't be read, or any file is invalid, in which
+    /// case nothing is registered
+    pub fn load_mocks_from_dir<P: AsRef<Path>>(
+        &mut self,
+        dir: P,
+    ) -> Result<Vec<MockHandle>, Error> {
+        let mut mocks = Vec::new();
+        for path in definitions::files(dir.as_ref())? {
+            mocks.extend(definitions::load(&path)?);
+        }
+        Ok(mocks.into_iter().map(|mock| self.register(mock)).collect())
     }
-    const fn error(&self) -> Option<&String> {
-        self.error.as_ref()
+
+    /// Registers a mock for every WireMock stub mapping in `root/mappings`, with body files
+    /// read from `root/__files`, as laid out by WireMock
+    ///
+    /// Requests may be matched with `url`, `urlPath`, `urlPathPattern`, `host`,
+    /// `queryParameters`, `headers` and `bodyPatterns`, using the `equalTo` (optionally
+    /// `caseInsensitive`), `contains`, `matches`, `absent`, `binaryEqualTo` and `equalToJson`
+    /// patterns. Responses may set `status`, `headers`, `body`, `jsonBody`, `base64Body`,
+    /// `bodyFileName`, `fixedDelayMilliseconds`, a uniform `delayDistribution` and a `fault`.
+    /// Mappings are tried in `priority` order, and must each set a `method` other than `ANY`
+    ///
+    /// # Errors
+    /// If a file can't be read or isn't valid, or a mapping uses a feature that isn't
+    /// supported, such as scenarios or templating, in which case nothing is registered
+    pub fn load_wiremock<P: AsRef<Path>>(&mut self, root: P) -> Result<Vec<MockHandle>, Error> {
+        let mocks = wiremock::load(root.as_ref())?;
+        Ok(mocks.into_iter().map(|mock| self.register(mock)).collect())
     }
 
-    fn from(stream: &mut dyn Read) -> Self {
-        let mut request = Self {
-            error: None,
-            host: None,
-            path: None,
-            method: None,
-            version: (0, 0),
-        };
+    /// Creates a new [`Proxy`] with a mock for every operation of the OpenAPI 3 JSON document
+    /// at `path`, as [`Proxy::load_openapi`] would
+    ///
+    /// # Errors
+    /// If the proxy can't be created, or the document can't be read or isn't valid
+    pub fn from_openapi<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut proxy = Self::try_new()?;
+        proxy.load_openapi(path)?;
+        Ok(proxy)
+    }
 
-        let mut all_buf = Vec::new();
+    /// Registers a mock for every operation of the OpenAPI 3 JSON document at `path`
+    ///
+    /// Each answers with the first success status it documents, and the example given for it,
+    /// or a sample built from its schema. Path templates such as `/users/{id}` match any value
+    /// of their parameters, after any concrete path of the document. Only the host and path of
+    /// the first server are matched, and only if its URL has no variables
+    ///
+    /// # Errors
+    /// If the document can't be read, isn't valid, or has references that can't be resolved
+    pub fn load_openapi<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<MockHandle>, Error> {
+        let mocks = openapi::load(path.as_ref())?;
+        Ok(mocks.into_iter().map(|mock| self.register(mock)).collect())
+    }
 
-        loop {
-            let mut buf = [0; 1024];
+    /// Every request received so far, in the order they arrived
+    ///
+    /// Includes requests no mock matched, and those answered with an error as they couldn't be
+    /// read, such as with `400 Bad Request` or `413 Payload Too Large`. Doesn't include the
+    /// `CONNECT` requests opening tunnels
+    pub fn received_requests(&self) -> Vec<RecordedRequest> {
+        self.journal.entries()
+    }
 
-            let rlen = match stream.read(&mut buf) {
-                Err(e) => Err(e.to_string()),
-                Ok(0) => Err("Nothing to read.".into()),
-                Ok(i) => Ok(i),
-            }
-            .map_err(|e| request.error = Some(e))
-            .unwrap_or(0);
-            if request.error().is_some() {
-                break;
-            }
+    /// The received requests for which `predicate` returns `true`
+    pub fn received_requests_matching<F>(&self, predicate: F) -> Vec<RecordedRequest>
+    where
+        F: Fn(&RecordedRequest) -> bool,
+    {
+        self.received_requests()
+            .into_iter()
+            .filter(|entry| predicate(entry))
+            .collect()
+    }
 
-            all_buf.extend_from_slice(&buf[..rlen]);
+    /// The received requests answered by the given mock
+    pub fn received_requests_for(&self, handle: &MockHandle) -> Vec<RecordedRequest> {
+        self.received_requests_matching(|entry| entry.mock_id() == Some(handle.id()))
+    }
 
-            if rlen < 1024 {
-                break;
-            }
+    /// The received requests no mock matched, including those that couldn't be read
+    pub fn unmatched_requests(&self) -> Vec<RecordedRequest> {
+        self.received_requests_matching(|entry| {
+            entry.mock_id().is_none() && !entry.is_passed_through()
+        })
+    }
+
+    /// Signs every leaf certificate with the same private key
+    ///
+    /// Saves generating a new key per hostname, at the cost of every intercepted host
+    /// presenting the same public key
+    ///
+    /// # Panics
+    /// If the key cannot be generated, see [`Proxy::try_with_shared_leaf_key`]
+    pub fn with_shared_leaf_key(&mut self) -> &mut Self {
+        self.try_with_shared_leaf_key()
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Signs every leaf certificate with the same private key, without panicking
+    ///
+    /// # Errors
+    /// If the key cannot be generated
+    pub fn try_with_shared_leaf_key(&mut self) -> Result<&mut Self, Error> {
+        self.certificates
+            .use_shared_leaf_key()
+            .map_err(|err| Error::Certificate(err.to_string()))?;
+        Ok(self)
+    }
+
+    /// Issues certificates for the given hosts ahead of time
+    ///
+    /// Certificates are otherwise issued the first time a host is tunnelled to, and kept
+    /// for the lifetime of the [`Proxy`]
+    /// # Errors
+    /// If a certificate cannot be issued
+    pub fn prewarm_certificates<I, S>(&mut self, hosts: I) -> Result<&mut Self, Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for host in hosts {
+            let host = host.as_ref();
+            self.certificates
+                .acceptor(host)
+                .map_err(|err| Error::Certificate(format!("{}: {}", host, err)))?;
         }
+        Ok(self)
+    }
 
-        if request.error().is_some() {
-            return request;
+    /// Start the proxy server
+    ///
+    /// # Panics
+    /// Will panic if proxy has already been started, or if it can't listen on the configured
+    /// address and port, see [`Proxy::try_start`]
+    pub fn start(&mut self) {
+        if let Err(err) = self.try_start() {
+            panic!("{}", err);
         }
+    }
 
-        let mut headers = [httparse::EMPTY_HEADER; 16];
-        let mut req = httparse::Request::new(&mut headers);
-
-        let _ = req
-            .parse(&all_buf)
-            .map_err(|err| {
-                request.error = Some(err.to_string());
-            })
-            .map(|result| match result {
-                httparse::Status::Complete(_head_length) => {
-                    request.method = req.method.map(|s| s.to_string());
-
-                    if req.method.as_ref().unwrap().eq(&"CONNECT") {
-                        request.host = req.path.unwrap().split(':').next().map(|f| f.to_string());
-                    } else {
-                        let (host, path) = split_url(
-                            &req.path
-                                .map(|f| f.to_string())
-                                .expect("Missing path in request"),
-                        );
-                        request.host = host;
-                        request.path = Some(path);
-                    }
+    /// Start the proxy server, without panicking
+    ///
+    /// # Errors
+    /// If proxy has already been started, or if it can't listen on the configured address and
+    /// port
+    pub fn try_start(&mut self) -> Result<(), Error> {
+        start_proxy(self)
+    }
 
-                    if let Some(a @ 0..=1) = req.version {
-                        request.version = (1, a);
-                    }
-                }
-                httparse::Status::Partial => panic!("Incomplete request"),
+    /// Stop the proxy server
+    ///
+    /// Closes idle kept-alive connections and waits for responses in flight to be written,
+    /// giving up on clients that stop reading them for the idle timeout, then joins the server threads and releases the listening port. The registered mocks
+    /// and root CA are kept, so the proxy can be started again afterwards.
+    ///
+    /// Does nothing if the proxy is not running.
+    pub fn stop(&mut self) {
+        let server = match self.server.take() {
+            Some(server) => server,
+            None => return,
+        };
+
+        server.shutdown.store(true, Ordering::SeqCst);
+        server.connections.close_all();
+        // the accept loop only checks the flag once it has a connection, so give it one
+        let mut wake = server.addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(match wake.ip() {
+                IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
             });
+        }
+        if let Err(err) = TcpStream::connect(wake) {
+            error!("Failed to wake server for shutdown: {}", err);
+        }
 
-        request
+        if server.handle.join().is_err() {
+            error!("Server thread panicked");
+        }
+        info!("Server at {} stopped", server.addr);
     }
-}
 
-fn create_identity(cn: &str, pair: &Cert) -> native_tls::Identity {
-    let password = "password";
+    /// Address and port of the local server.
+    /// Can be used with [`std::net::TcpStream`].
+    ///
+    /// # Panics
+    /// If server is not running
+    pub fn address(&self) -> SocketAddr {
+        self.server
+            .as_ref()
+            .map(|server| server.addr)
+            .expect("server should be listening")
+    }
 
-    let encrypted = OpensslInterface::new()
-        .mk_ca_signed_cert(cn, pair, password)
-        .unwrap();
+    /// A local `http://…` URL of the server.
+    ///
+    /// # Panics
+    /// If server is not running
+    pub fn url(&self) -> String {
+        format!("http://{}", self.address())
+    }
 
-    native_tls::Identity::from_pkcs12(&encrypted, password).expect("Unable to build identity")
+    /// Returns the root CA certificate of the server
+    ///
+    /// # Panics
+    /// If PEM conversion fails
+    pub fn get_certificate(&self) -> Vec<u8> {
+        self.certificates.ca().cert()
+    }
 }
 
-fn start_proxy(proxy: &mut Proxy) {
-    if proxy.started {
-        panic!("Tried to start an already started proxy");
-    }
-    proxy.started = true;
-    let mocks = proxy.mocks.clone();
-    let cert = proxy.cert.clone();
-
-    // if state.listening_addr.is_some() {
-    //     return;
-    // }
-
-    let (tx, rx) = mpsc::channel();
-
-    thread::spawn(move || {
-        let res = TcpListener::bind(SERVER_ADDRESS_INTERNAL).or_else(|err| {
-            error!("TcpListener::bind: {}", err);
-            TcpListener::bind("127.0.0.1:0")
-        });
-        let (listener, addr) = match res {
-            Ok(listener) => {
-                let addr = listener.local_addr().unwrap();
-                tx.send(Some(addr)).unwrap();
-                (listener, addr)
-            }
-            Err(err) => {
-                error!("alt bind: {}", err);
-                tx.send(None).unwrap();
-                return;
-            }
-        };
+fn start_proxy(proxy: &mut Proxy) -> Result<(), Error> {
+    if proxy.server.is_some() {
+        return Err(Error::AlreadyStarted);
+    }
+    let recorder = proxy
+        .config
+        .recording
+        .as_deref()
+        .map(Recorder::new)
+        .transpose()?;
+    let shared = Arc::new(Shared {
+        certificates: Arc::clone(&proxy.certificates),
+        mocks: Arc::clone(&proxy.mocks),
+        journal: proxy.journal.clone(),
+        limits: proxy.config.limits,
+        default_delay: proxy.config.default_delay,
+        passthrough: proxy.config.passthrough.clone(),
+        upstream: proxy.config.upstream,
+        upstream_timeout: proxy.config.upstream_timeout,
+        recorder,
+    });
+    let config = proxy.config.clone();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server_shutdown = Arc::clone(&shutdown);
+    let connections = Connections::default();
+    let server_connections = connections.clone();
 
+    let bind_error = |source| Error::Bind {
+        address: config.requested_address(),
+        source,
+    };
+    let listener = config.bind().map_err(bind_error)?;
+    let addr = listener.local_addr().map_err(bind_error)?;
+
+    let handle = thread::spawn(move || {
         info!("Server is listening at {}", addr);
-        for stream in listener.incoming() {
+        let mut pool = ThreadPool::new(config.worker_threads);
+        for (id, stream) in listener.incoming().enumerate() {
+            if server_shutdown.load(Ordering::SeqCst) {
+                break;
+            }
             info!("Got stream: {:?}", stream);
-            if let Ok(mut stream) = stream {
-                let request = Request::from(&mut stream);
-                info!("Request received: {}", request);
-                if request.is_ok() {
-                    handle_request(cert.to_owned(), &mocks, request, stream).unwrap();
-                } else {
-                    let message = request
-                        .error()
-                        .map_or("Could not parse the request.", |err| err.as_str());
-                    error!("Could not parse request because: {}", message);
-                    respond_with_error(&mut stream as &mut dyn Write, &request, message).unwrap();
+            if let Ok(stream) = stream {
+                // also bounds writes, so a client that stops reading can't hold up `stop`
+                let timeouts = stream
+                    .set_read_timeout(Some(config.idle_timeout))
+                    .and_then(|()| stream.set_write_timeout(Some(config.idle_timeout)));
+                if let Err(err) = timeouts {
+                    error!("Could not set idle timeout: {}", err);
                 }
+                server_connections.insert(id, &stream);
+
+                let shared = Arc::clone(&shared);
+                let connections = server_connections.clone();
+                pool.execute(move || {
+                    let _release = connections.release_on_drop(id);
+                    handle_connection(&shared, stream);
+                });
             } else {
                 error!("Could not read from stream");
             }
         }
+        pool.join();
+    });
+
+    proxy.server = Some(Server {
+        addr,
+        shutdown,
+        connections,
+        handle,
     });
+    Ok(())
+}
 
-    proxy.listening_addr = rx.recv().ok().and_then(|addr| addr);
+fn handle_connection(shared: &Shared, mut stream: TcpStream) {
+    let mut pending = Vec::new();
+    let request = Request::from(&mut stream, &mut pending, shared.limits);
+    info!("Request received: {}", request);
+    if request.closed {
+        info!("Connection closed before sending a request");
+    } else if request.is_ok() {
+        if let Err(err) = handle_request(shared, request, stream, pending) {
+            error!("Failed to handle connection: {}", err);
+        }
+    } else if let Err(err) = respond_with_error(&mut stream, &request, shared, Scheme::Http) {
+        error!("Failed to respond with error: {}", err);
+    }
 }
 
 fn open_tunnel<'a>(
-    identity: Cert,
+    certificates: &Certificates,
     request: &Request,
     stream: &'a mut TcpStream,
 ) -> Result<TlsStream<&'a mut TcpStream>, Box<dyn std::error::Error>> {
+    write_tunnel_established(request, stream)?;
+
+    let acceptor = certificates.acceptor(request.host.as_ref().expect("No host??"))?;
+
+    info!("Wrapping with tls");
+    let tstream = match acceptor.accept(stream) {
+        Ok(tstream) => tstream,
+        Err(native_tls::HandshakeError::Failure(err)) => return Err(err.into()),
+        Err(native_tls::HandshakeError::WouldBlock(_)) => {
+            return Err("TLS handshake did not complete".into())
+        }
+    };
+    info!("Wrapped: {:?}", tstream);
+
+    Ok(tstream)
+}
+
+fn write_tunnel_established(
+    request: &Request,
+    stream: &mut TcpStream,
+) -> Result<(), Box<dyn std::error::Error>> {
     let version = request.version;
     let status = 200;
 
@@ -287,94 +762,309 @@ fn open_tunnel<'a>(
     stream.write_all(&response)?;
     stream.flush()?;
     info!("Tunnel open response written");
+    Ok(())
+}
 
-    let identity = create_identity(request.host.as_ref().expect("No host??"), &identity);
-
-    info!("Wrapping with tls");
-    let tstream = native_tls::TlsAcceptor::builder(identity)
-        .build()
-        .expect("Unable to build acceptor")
-        .accept(stream)
-        .expect("Unable to accept connection");
-    info!("Wrapped: {:?}", tstream);
-
-    Ok(tstream)
+/// What happens to a connection once a request has been answered
+enum Next {
+    KeepAlive,
+    Close,
+    Fault(Fault),
 }
 
 fn handle_request(
-    identity: Cert,
-    mocks: &[Mock],
+    shared: &Shared,
     request: Request,
     mut stream: TcpStream,
+    mut pending: Vec<u8>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    if request.method.as_ref().unwrap().eq("CONNECT") {
-        let mut tea = open_tunnel(identity, &request, &mut stream)?;
+    let fault = if request.method.as_ref().unwrap().eq("CONNECT") {
+        let mocks = shared.mocks.read().unwrap();
+        // only the first mock for the host decides, as it would for requests in the tunnel
+        let refuse = mocks
+            .iter()
+            .find(|m| m.is_for_host(&request))
+            .is_some_and(|m| m.refuses_tls(&request));
+        drop(mocks);
+        if refuse {
+            info!(
+                "Closing tunnel to {:?} before the TLS handshake",
+                request.host
+            );
+            return write_tunnel_established(&request, &mut stream);
+        }
 
-        let mut req = Request::from(&mut tea);
-        req.host = request.host;
-        if !req.is_ok() {
-            return Err(req.error().unwrap().as_str().into());
-        };
+        let mut tea = open_tunnel(&shared.certificates, &request, &mut stream)?;
 
-        // TODO: should probably loop reading of requests here for #23
-        _handle_request(&mut tea, req, mocks)
+        // clients wait for the tunnel to be open before sending anything through it
+        let mut pending = Vec::new();
+        loop {
+            let mut req = Request::from(&mut tea, &mut pending, shared.limits);
+            if req.closed {
+                return Ok(());
+            }
+            req.host = request.host.clone();
+            req.port = request.port;
+            if !req.is_ok() {
+                return respond_with_error(&mut tea, &req, shared, Scheme::Https);
+            };
+
+            match _handle_request(&mut tea, &req, shared, Scheme::Https)? {
+                Next::KeepAlive => {}
+                Next::Close => return Ok(()),
+                Next::Fault(fault) => break fault,
+            }
+        }
     } else {
-        _handle_request(&mut stream, request, mocks)
-    }
+        let mut request = request;
+        loop {
+            match _handle_request(&mut stream, &request, shared, Scheme::Http)? {
+                Next::KeepAlive => {}
+                Next::Close => return Ok(()),
+                Next::Fault(fault) => break fault,
+            }
+
+            request = Request::from(&mut stream, &mut pending, shared.limits);
+            if request.closed {
+                return Ok(());
+            }
+            if !request.is_ok() {
+                return respond_with_error(&mut stream, &request, shared, Scheme::Http);
+            }
+        }
+    };
+
+    info!("Injecting {:?}", fault);
+    fault::finish(&mut stream, fault)?;
+    Ok(())
 }
 
 fn _handle_request<S: Read + Write>(
     tstream: &mut S,
-    req: Request,
-    mocks: &[Mock],
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut matched = false;
-    for m in mocks {
-        if m.matches(&req) {
-            write_response(tstream, &req, &m.response)?;
-            matched = true;
-            break;
+    req: &Request,
+    shared: &Shared,
+    scheme: Scheme,
+) -> Result<Next, Box<dyn std::error::Error>> {
+    let mocks = shared.mocks.read().unwrap();
+    let served = mocks
+        .iter()
+        .find_map(|m| m.serve(req).map(|response| (m.id, response.into_owned())));
+    let forward = served.is_none() && shared.passthrough.allows(req.host());
+    let report = (served.is_none() && !forward).then(|| diagnostics::near_misses(req, &mocks));
+    // not held while writing, so slow clients don't hold up changes to the mocks
+    drop(mocks);
+
+    let (mock_id, response) = match (served, report) {
+        (Some((id, response)), _) => (Some(id), response),
+        (None, Some(report)) => {
+            error!("{}: {}", diagnostics::describe(req), report);
+            (None, error_response(&report))
         }
-    }
+        (None, None) => {
+            info!("Forwarding {}", diagnostics::describe(req));
+            let response =
+                match passthrough::forward(req, scheme, shared.upstream, shared.upstream_timeout) {
+                    Ok(response) => {
+                        if let Some(recorder) = &shared.recorder {
+                            match recorder.save(req, scheme, &response) {
+                                Ok(path) => info!("Recorded to {}", path.display()),
+                                Err(err) => error!("Failed to record: {}", err),
+                            }
+                        }
+                        response
+                    }
+                    Err(err) => bad_gateway(req, &*err),
+                };
+            (None, response)
+        }
+    };
+
+    let entry = RecordedRequest::new(req.clone(), scheme, mock_id, response.clone());
+    shared.journal.record(if forward {
+        entry.passed_through()
+    } else {
+        entry
+    });
 
-    if !matched {
-        respond_with_error(tstream, &req, "No matching response")?;
+    let delay = response
+        .latency
+        .first_byte_delay()
+        .unwrap_or(shared.default_delay);
+    if !delay.is_zero() {
+        thread::sleep(delay);
     }
 
-    Ok(())
+    match response.fault {
+        None if write_response(tstream, req, &response)? => Ok(Next::KeepAlive),
+        None => Ok(Next::Close),
+        Some(Fault::GarbageBytes) => {
+            tstream.write_all(&fault::garbage())?;
+            tstream.flush()?;
+            Ok(Next::Fault(Fault::GarbageBytes))
+        }
+        Some(fault @ Fault::TruncateBodyAfter(_)) => {
+            write_response(tstream, req, &response)?;
+            Ok(Next::Fault(fault))
+        }
+        Some(fault) => Ok(Next::Fault(fault)),
+    }
 }
 
+/// Writes `response`, returning whether the connection can be used for another request
+///
+/// Adds `Content-Length`, `Date` and `Connection` unless the response sets them itself.
+/// Streamed bodies, and bodies of responses with `Transfer-Encoding: chunked`, are sent in
+/// chunks, or until the connection is closed for HTTP/1.0 clients
 fn write_response(
     tstream: &mut dyn Write,
     request: &Request,
     response: &Response,
-) -> Result<(), Box<dyn std::error::Error>> {
-    tstream.write_fmt(format_args!(
-        "HTTP/1.{} {}\r\n",
-        request.version.1, response.status
-    ))?;
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let status = response.status;
+    let has_token = |name: &str, token: &str| {
+        response.header_values(name).any(|value| {
+            value
+                .split(',')
+                .any(|part| part.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    let streamed = response.stream.is_some();
+    let chunked_header = has_token("transfer-encoding", "chunked");
+    let chunked = (chunked_header || streamed) && request.version.1 == 1;
+    // without chunks, only closing the connection can mark the end of a streamed body
+    let close_delimited = streamed && !chunked;
+    let keep_alive = request.is_ok()
+        && request.keep_alive()
+        && response.fault.is_none()
+        && !close_delimited
+        && !has_token("connection", "close");
+    // these never have a body, whatever the response says
+    let bodyless = status.is_informational()
+        || status == http::StatusCode::NO_CONTENT
+        || status == http::StatusCode::NOT_MODIFIED;
+    let send_body = !bodyless && request.method() != "HEAD";
+
+    tstream.write_fmt(format_args!("HTTP/1.{} {}\r\n", request.version.1, status))?;
     for (header, value) in &response.headers {
         tstream.write_fmt(format_args!("{}: {}\r\n", header, value))?;
     }
-    tstream.write_all(b"\r\n")?;
-    tstream.write_all(&response.body)?;
+    if !response.has_header("date") {
+        let date = chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT");
+        tstream.write_fmt(format_args!("Date: {}\r\n", date))?;
+    }
+    if !bodyless {
+        if chunked && !chunked_header {
+            tstream.write_all(b"Transfer-Encoding: chunked\r\n")?;
+        }
+        if chunked && !response.trailers.is_empty() && !response.has_header("trailer") {
+            let names: Vec<_> = response
+                .trailers
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect();
+            tstream.write_fmt(format_args!("Trailer: {}\r\n", names.join(", ")))?;
+        }
+        if !chunked && !streamed && !response.has_header("content-length") {
+            tstream.write_fmt(format_args!("Content-Length: {}\r\n", response.body.len()))?;
+        }
+    }
+    if !response.has_header("connection") {
+        if !keep_alive {
+            tstream.write_all(b"Connection: close\r\n")?;
+        } else if request.version.1 == 0 {
+            tstream.write_all(b"Connection: keep-alive\r\n")?;
+        }
+    }
     tstream.write_all(b"\r\n")?;
 
-    Ok(())
+    if send_body {
+        let length = if streamed {
+            None
+        } else {
+            Some(response.body.len())
+        };
+        let mut paced;
+        let out = match response.latency.rate(length) {
+            Some(rate) => {
+                paced = Paced::new(tstream, rate);
+                &mut paced as &mut dyn Write
+            }
+            None => tstream,
+        };
+        let mut truncated;
+        let out = match response.fault {
+            Some(Fault::TruncateBodyAfter(remaining)) => {
+                truncated = Truncated {
+                    inner: out,
+                    remaining,
+                };
+                &mut truncated as &mut dyn Write
+            }
+            _ => out,
+        };
+
+        match &response.stream {
+            Some(body) => body.write_to(out, chunked, response.chunk_delay, &response.trailers)?,
+            None if chunked => StreamedBody::Chunks(vec![response.body.clone()]).write_to(
+                out,
+                true,
+                response.chunk_delay,
+                &response.trailers,
+            )?,
+            None => out.write_all(&response.body)?,
+        }
+        out.flush()?;
+    } else {
+        tstream.flush()?;
+    }
+
+    Ok(keep_alive)
 }
 
+/// Answers a request that could not be read, after which the connection is closed
+/// Answers a request that couldn't be read, recording it with the error status
 fn respond_with_error(
-    _stream: &mut dyn Write,
+    stream: &mut dyn Write,
     request: &Request,
-    message: &str,
+    shared: &Shared,
+    scheme: Scheme,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    write_response(
-        _stream,
-        request,
-        &Response {
-            headers: vec![],
-            status: http::StatusCode::INTERNAL_SERVER_ERROR,
-            body: message.as_bytes().to_vec(),
-        },
-    )
+    let message = request
+        .error()
+        .map_or("Could not parse the request.", |err| err.as_str());
+    error!("Could not parse request because: {}", message);
+
+    let mut response = error_response(message);
+    response.status = request.error_status;
+    shared.journal.record(RecordedRequest::new(
+        request.clone(),
+        scheme,
+        None,
+        response.clone(),
+    ));
+    write_response(stream, request, &response)?;
+    Ok(())
+}
+
+fn bad_gateway(request: &Request, err: &(dyn std::error::Error + 'static)) -> Response {
+    error!(
+        "Failed to forward {}: {}",
+        diagnostics::describe(request),
+        err
+    );
+    let mut response = error_response(&format!("Failed to forward request: {}", err));
+    response.status = if passthrough::is_timeout(err) {
+        http::StatusCode::GATEWAY_TIMEOUT
+    } else {
+        http::StatusCode::BAD_GATEWAY
+    };
+    response
+}
+
+fn error_response(message: &str) -> Response {
+    Response {
+        status: http::StatusCode::INTERNAL_SERVER_ERROR,
+        body: message.as_bytes().to_vec(),
+        ..Response::default()
+    }
 }
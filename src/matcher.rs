@@ -0,0 +1,143 @@
+use regex::bytes::Regex;
+
+/// Describes how part of a request is compared by a [`Mock`](crate::Mock)
+#[derive(Debug, Clone)]
+pub enum Matcher {
+    /// The value must be exactly equal to the given string
+    Exact(String),
+    /// The value must be exactly equal to the given bytes
+    Bytes(Vec<u8>),
+    /// The value must contain the given string
+    Contains(String),
+    /// The value must match the given regular expression
+    Regex(String),
+    /// The value must be JSON equal to the given value, ignoring formatting and key order
+    Json(json::JsonValue),
+    /// The value must be JSON containing at least the given value
+    ///
+    /// Objects may have extra keys, arrays must have the same length with each element
+    /// containing the expected one
+    PartialJson(json::JsonValue),
+    /// The value must be present, whatever it is
+    Present,
+    /// The value must not be present at all
+    Absent,
+}
+
+impl Matcher {
+    /// Checks that a [`Matcher::Regex`] holds a valid regular expression
+    pub(crate) fn validate(&self) -> Result<(), regex::Error> {
+        match self {
+            Self::Regex(pattern) => Regex::new(pattern).map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Compiles a [`Matcher::Regex`] once, rather than for every request compared
+    pub(crate) fn compile(self) -> Result<Compiled, regex::Error> {
+        let regex = match &self {
+            Self::Regex(pattern) => Some(Regex::new(pattern)?),
+            _ => None,
+        };
+        Ok(Compiled {
+            matcher: self,
+            regex,
+        })
+    }
+}
+
+/// A [`Matcher`] ready to compare requests, as held by a [`Mock`](crate::Mock)
+#[derive(Debug, Clone)]
+pub(crate) struct Compiled {
+    matcher: Matcher,
+    regex: Option<Regex>,
+}
+
+impl Compiled {
+    /// Matches against every value a request has for a given header
+    ///
+    /// A header sent more than once matches if any of its values match
+    pub(crate) fn matches_values(&self, values: &[&str]) -> bool {
+        match self.matcher {
+            Matcher::Present => !values.is_empty(),
+            Matcher::Absent => values.is_empty(),
+            _ => values
+                .iter()
+                .any(|value| self.matches_bytes(value.as_bytes())),
+        }
+    }
+
+    /// Matches against a request body, where an empty body counts as absent
+    pub(crate) fn matches_body(&self, body: &[u8]) -> bool {
+        match self.matcher {
+            Matcher::Present => !body.is_empty(),
+            Matcher::Absent => body.is_empty(),
+            _ => self.matches_bytes(body),
+        }
+    }
+
+    fn matches_bytes(&self, value: &[u8]) -> bool {
+        match &self.matcher {
+            Matcher::Exact(expected) => value == expected.as_bytes(),
+            Matcher::Bytes(expected) => value == expected.as_slice(),
+            Matcher::Contains(needle) => {
+                needle.is_empty()
+                    || value
+                        .windows(needle.len())
+                        .any(|window| window == needle.as_bytes())
+            }
+            Matcher::Regex(_) => self.regex.as_ref().is_some_and(|re| re.is_match(value)),
+            Matcher::Json(expected) => parse_json(value).is_some_and(|actual| &actual == expected),
+            Matcher::PartialJson(expected) => {
+                parse_json(value).is_some_and(|actual| json_contains(&actual, expected))
+            }
+            Matcher::Present | Matcher::Absent => self.matches_body(value),
+        }
+    }
+}
+
+impl std::fmt::Display for Compiled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.matcher.fmt(f)
+    }
+}
+
+fn parse_json(value: &[u8]) -> Option<json::JsonValue> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| json::parse(value).ok())
+}
+
+/// Whether `actual` contains everything in `expected`
+fn json_contains(actual: &json::JsonValue, expected: &json::JsonValue) -> bool {
+    if expected.is_object() {
+        actual.is_object()
+            && expected
+                .entries()
+                .all(|(key, value)| actual.has_key(key) && json_contains(&actual[key], value))
+    } else if expected.is_array() {
+        actual.is_array()
+            && actual.len() == expected.len()
+            && actual
+                .members()
+                .zip(expected.members())
+                .all(|(actual, expected)| json_contains(actual, expected))
+    } else {
+        actual == expected
+    }
+}
+
+impl std::fmt::Display for Matcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exact(expected) => write!(f, "{:?}", expected),
+            Self::Bytes(expected) => write!(f, "{} specific bytes", expected.len()),
+            Self::Contains(needle) => write!(f, "something containing {:?}", needle),
+            Self::Regex(pattern) => write!(f, "something matching /{}/", pattern),
+            Self::Json(expected) => write!(f, "JSON equal to {}", expected.dump()),
+            Self::PartialJson(expected) => write!(f, "JSON containing {}", expected.dump()),
+            Self::Present => write!(f, "any value"),
+            Self::Absent => write!(f, "nothing"),
+        }
+    }
+}
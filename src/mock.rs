@@ -1,21 +1,236 @@
-use crate::Request;
+use crate::latency::Latency;
+use crate::matcher::Compiled;
+use crate::streaming::StreamedBody;
+use crate::{Error, Fault, Matcher, Request};
 use http::status::StatusCode;
+use std::borrow::Cow;
 use std::convert::TryInto;
+use std::io::Read;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
+/// A response returned by a [`Mock`]
 #[derive(Debug, Clone, Default)]
 pub struct Response {
     pub(super) headers: Vec<(String, String)>,
     pub(super) body: Vec<u8>,
+    /// Takes the place of `body` when set
+    pub(super) stream: Option<StreamedBody>,
+    pub(super) chunk_delay: Duration,
+    pub(super) trailers: Vec<(String, String)>,
+    pub(super) latency: Latency,
+    pub(super) fault: Option<Fault>,
     pub(super) status: StatusCode,
 }
 
-pub fn split_url(url: &str) -> (Option<String>, String) {
+impl Response {
+    /// Builds an empty 200 OK [`Response`]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the response body
+    pub fn with_body<B>(&mut self, body: B) -> &mut Self
+    where
+        B: Into<Vec<u8>>,
+    {
+        self.body = body.into();
+        self.stream = None;
+        self
+    }
+
+    /// Sets the response body to anything with [`Into<json::JsonValue>`]
+    pub fn with_body_from_json<T>(&mut self, value: T) -> &mut Self
+    where
+        T: Into<json::JsonValue>,
+    {
+        self.body = json::stringify_pretty(value, 2).as_bytes().to_vec();
+        self.stream = None;
+        self
+    }
+
+    /// Sends the body in the given chunks, with `Transfer-Encoding: chunked`
+    ///
+    /// Each chunk is flushed to the client separately. Empty chunks are skipped
+    pub fn with_chunked_body<I, C>(&mut self, chunks: I) -> &mut Self
+    where
+        I: IntoIterator<Item = C>,
+        C: Into<Vec<u8>>,
+    {
+        let chunks = chunks.into_iter().map(Into::into).collect();
+        self.stream = Some(StreamedBody::Chunks(chunks));
+        self
+    }
+
+    /// Streams the body from `reader`, with `Transfer-Encoding: chunked`
+    ///
+    /// The first response sent reads from `reader` as it goes, any later ones replay the
+    /// same bytes
+    pub fn with_body_reader<R>(&mut self, reader: R) -> &mut Self
+    where
+        R: Read + Send + 'static,
+    {
+        self.stream = Some(StreamedBody::from_reader(Box::new(reader)));
+        self
+    }
+
+    /// Waits between the chunks of a chunked or streamed body
+    pub const fn with_chunk_delay(&mut self, delay: Duration) -> &mut Self {
+        self.chunk_delay = delay;
+        self
+    }
+
+    /// Waits before sending the response
+    ///
+    /// Takes the place of the proxy's default delay, see [`ProxyBuilder::with_default_delay`]
+    ///
+    /// [`ProxyBuilder::with_default_delay`]: crate::ProxyBuilder::with_default_delay
+    pub const fn with_delay(&mut self, delay: Duration) -> &mut Self {
+        self.latency.delay = Some((delay, delay));
+        self
+    }
+
+    /// Waits a random time between `min` and `max` before sending the response
+    pub const fn with_delay_range(&mut self, min: Duration, max: Duration) -> &mut Self {
+        self.latency.delay = Some((min, max));
+        self
+    }
+
+    /// Spreads sending the body over `duration`, after the head was sent right away
+    ///
+    /// Together with [`Response::with_delay`] this separates the time to first byte from
+    /// the time taken by the whole response. Ignored for chunked and streamed bodies, whose
+    /// pace is set by [`Response::with_chunk_delay`]
+    pub const fn with_body_duration(&mut self, duration: Duration) -> &mut Self {
+        self.latency.body_duration = Some(duration);
+        self
+    }
+
+    /// Sends the body no faster than `bytes_per_second`, like a slow network would
+    pub const fn with_throttle(&mut self, bytes_per_second: u64) -> &mut Self {
+        self.latency.bytes_per_second = Some(bytes_per_second);
+        self
+    }
+
+    /// Simulates a network failure instead of responding normally
+    ///
+    /// Delays set with [`Response::with_delay`] still apply before the failure
+    pub const fn with_fault(&mut self, fault: Fault) -> &mut Self {
+        self.fault = Some(fault);
+        self
+    }
+
+    /// Adds a trailer, sent after the last chunk of a chunked or streamed body
+    ///
+    /// Trailers are dropped for HTTP/1.0 clients, which don't support chunked bodies
+    pub fn with_trailer<K, V>(&mut self, name: K, value: V) -> &mut Self
+    where
+        K: ToString,
+        V: ToString,
+    {
+        self.trailers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Adds a header to the response
+    ///
+    /// Does not remove existing headers with the same name
+    pub fn with_header<K, V>(&mut self, name: K, value: V) -> &mut Self
+    where
+        K: ToString,
+        V: ToString,
+    {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets the response status
+    /// Default is 200 OK
+    ///
+    /// # Panics
+    /// If `status` isn't a valid status code, see [`Response::try_with_status`]
+    pub fn with_status<T>(&mut self, status: T) -> &mut Self
+    where
+        T: TryInto<http::StatusCode>,
+    {
+        self.status = status.try_into().unwrap_or_else(|_| panic!("Bad status"));
+        self
+    }
+
+    /// Sets the response status, without panicking
+    ///
+    /// # Errors
+    /// If `status` isn't a valid status code
+    pub fn try_with_status<T>(&mut self, status: T) -> Result<&mut Self, Error>
+    where
+        T: TryInto<http::StatusCode>,
+        T::Error: std::fmt::Display,
+    {
+        self.status = status
+            .try_into()
+            .map_err(|err| Error::InvalidStatus(err.to_string()))?;
+        Ok(self)
+    }
+
+    /// Freezes the given [`Response`]
+    pub fn create(&self) -> Self {
+        self.clone()
+    }
+
+    /// The body as sent, as far as it is known for a streamed one
+    pub(super) fn body_bytes(&self) -> Cow<'_, [u8]> {
+        self.stream.as_ref().map_or_else(
+            || Cow::Borrowed(self.body.as_slice()),
+            |stream| Cow::Owned(stream.collected()),
+        )
+    }
+
+    /// Whether a header with the given name was set, compared case-insensitively
+    pub(super) fn has_header(&self, name: &str) -> bool {
+        self.header_values(name).next().is_some()
+    }
+
+    /// All values of the given header, compared case-insensitively
+    pub(super) fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.headers
+            .iter()
+            .filter(move |(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// What a [`Mock`] does once every response in its sequence has been sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Exhausted {
+    /// Keep sending the last response
+    #[default]
+    RepeatLast,
+    /// Start again from the first response
+    Cycle,
+    /// Stop matching, letting the request fall through to the next matching mock
+    FallThrough,
+    /// Respond with a 500 error
+    Error,
+}
+
+/// Builds a [`Response`] for each request a [`Mock`] matches
+#[derive(Clone)]
+struct Responder(Arc<dyn Fn(&Request) -> Response + Send + Sync>);
+
+impl std::fmt::Debug for Responder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Responder")
+    }
+}
+
+/// Splits a URL, or a path relative to any host, into its host and its path with the query
+///
+/// IPv6 hosts are given without their brackets, as in a `CONNECT` target
+pub fn split_url(url: &str) -> Result<(Option<String>, String), url::ParseError> {
     let fake_base = url::Url::from_str("https://fake_base.com").unwrap();
-    let url = url::Url::options()
-        .base_url(Some(&fake_base))
-        .parse(url)
-        .expect("failed to parse");
+    let url = url::Url::options().base_url(Some(&fake_base)).parse(url)?;
 
     let mut qs = &url::form_urlencoded::Serializer::new(String::new())
         .extend_pairs(url.query_pairs())
@@ -28,37 +243,147 @@ pub fn split_url(url: &str) -> (Option<String>, String) {
 
     let host = if url.host() == fake_base.host() {
         None
+    } else if let Some(url::Host::Ipv6(address)) = url.host() {
+        Some(address.to_string())
     } else {
         url.host().map(|f| f.to_string())
     };
 
     let path = url.path().to_string() + qs;
 
-    (host, path)
+    Ok((host, path))
+}
+
+/// How many times a [`Mock`] is expected to be hit
+#[derive(Debug, Clone, Copy, Default)]
+struct Expectation {
+    at_least: usize,
+    at_most: Option<usize>,
+}
+
+impl Expectation {
+    fn is_satisfied(self, hits: usize) -> bool {
+        hits >= self.at_least && self.at_most.is_none_or(|at_most| hits <= at_most)
+    }
+}
+
+impl std::fmt::Display for Expectation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.at_most {
+            Some(at_most) if at_most == self.at_least => write!(f, "exactly {}", at_most),
+            Some(at_most) if self.at_least == 0 => write!(f, "at most {}", at_most),
+            Some(at_most) => write!(f, "between {} and {}", self.at_least, at_most),
+            None => write!(f, "at least {}", self.at_least),
+        }
+    }
+}
+
+/// Returned when registering a [`Mock`], to check how often it was hit
+#[derive(Debug, Clone)]
+pub struct MockHandle {
+    id: usize,
+    description: String,
+    expected: Expectation,
+    hits: Arc<AtomicUsize>,
+}
+
+impl MockHandle {
+    /// Identifies the [`Mock`] within its [`Proxy`](crate::Proxy)
+    pub const fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Number of requests the [`Mock`] has responded to so far
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+
+    /// Describes the unmet expectation, if the [`Mock`] was hit too few or too many times
+    pub(super) fn unsatisfied(&self) -> Option<String> {
+        let hits = self.hits();
+        if self.expected.is_satisfied(hits) {
+            None
+        } else {
+            Some(format!(
+                "{} was expected to be hit {} time(s), but was hit {} time(s)",
+                self.description, self.expected, hits
+            ))
+        }
+    }
+
+    /// Checks the [`Mock`] was hit as many times as expected
+    ///
+    /// # Panics
+    /// If the expectation set with [`Mock::expect`] and friends wasn't met
+    pub fn assert(&self) {
+        if let Some(message) = self.unsatisfied() {
+            panic!("{}", message);
+        }
+    }
 }
 
 /// The struct used to define mock responses
 #[derive(Debug, Clone)]
 pub struct Mock {
+    /// Assigned when registered with a [`Proxy`](crate::Proxy)
+    pub(super) id: usize,
     /// The path to match again
     pub(super) path: String,
     /// The HTTP method to match again
     pub(super) method: String,
     /// The response to return
     pub(super) response: Response,
+    /// Builds the response instead, when set
+    responder: Option<Responder>,
+    /// Responses to send in turn instead, when not empty
+    sequence: Vec<Response>,
+    exhausted: Exhausted,
     pub(super) host: Option<String>,
+    /// Request headers to match against, by lowercased name
+    pub(super) headers: Vec<(String, Compiled)>,
+    /// Matches the path without its query string instead of `path`, when set
+    pub(super) path_matcher: Option<Compiled>,
+    /// Query string parameters to match against, replacing the query in `path` when set
+    pub(super) query: Vec<(String, Compiled)>,
+    /// Matchers the request body must satisfy
+    pub(super) body: Vec<Compiled>,
+    expected: Expectation,
+    /// Shared between every clone, so hits counted by the server are visible to handles
+    hits: Arc<AtomicUsize>,
 }
 impl Mock {
     /// Builds a [`Mock`] with the given `method` and `path` and a [`Default`] [`Response`]
+    ///
+    /// # Panics
+    /// If `path` is neither a path nor a valid URL, see [`Mock::try_new`]
     pub fn new(method: &str, path: &str) -> Self {
-        let (host, path) = split_url(path);
+        Self::try_new(method, path).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Builds a [`Mock`] with the given `method` and `path`, without panicking
+    ///
+    /// # Errors
+    /// If `path` is neither a path nor a valid URL
+    pub fn try_new(method: &str, path: &str) -> Result<Self, Error> {
+        let (host, path) =
+            split_url(path).map_err(|err| Error::InvalidUrl(format!("{:?}: {}", path, err)))?;
 
-        Self {
+        Ok(Self {
+            id: 0,
             method: method.to_string(),
             path,
             host,
             response: Response::default(),
-        }
+            responder: None,
+            sequence: Vec::new(),
+            exhausted: Exhausted::default(),
+            headers: Vec::new(),
+            path_matcher: None,
+            query: Vec::new(),
+            body: Vec::new(),
+            expected: Expectation::default(),
+            hits: Arc::default(),
+        })
     }
 
     /// Reads the response body from disk
@@ -68,7 +393,7 @@ impl Mock {
         &mut self,
         filename: &str,
     ) -> Result<&mut Self, Box<dyn std::error::Error>> {
-        self.response.body = std::fs::read(filename)?;
+        self.response.with_body(std::fs::read(filename)?);
         Ok(self)
     }
 
@@ -80,7 +405,7 @@ impl Mock {
     where
         T: Into<json::JsonValue>,
     {
-        self.response.body = json::stringify_pretty(value, 2).as_bytes().to_vec();
+        self.response.with_body_from_json(value);
         Ok(self)
     }
 
@@ -92,43 +417,451 @@ impl Mock {
         K: ToString,
         V: ToString,
     {
-        self.response
-            .headers
-            .push((name.to_string(), value.to_string()));
+        self.response.with_header(name, value);
         self
     }
 
     /// Sets the response status
     /// Default is 200 OK
+    ///
+    /// # Panics
+    /// If `status` isn't a valid status code, see [`Mock::try_with_status`]
     pub fn with_status<T>(&mut self, status: T) -> &mut Self
     where
         T: TryInto<http::StatusCode>,
     {
-        self.response.status = match status.try_into() {
-            Ok(status) => status,
-            Err(_) => panic!("Bad status"),
+        self.response.with_status(status);
+        self
+    }
+
+    /// Sets the response status, without panicking
+    ///
+    /// # Errors
+    /// If `status` isn't a valid status code
+    pub fn try_with_status<T>(&mut self, status: T) -> Result<&mut Self, Error>
+    where
+        T: TryInto<http::StatusCode>,
+        T::Error: std::fmt::Display,
+    {
+        self.response.try_with_status(status)?;
+        Ok(self)
+    }
+
+    /// Sends the body in the given chunks, see [`Response::with_chunked_body`]
+    pub fn with_chunked_body<I, C>(&mut self, chunks: I) -> &mut Self
+    where
+        I: IntoIterator<Item = C>,
+        C: Into<Vec<u8>>,
+    {
+        self.response.with_chunked_body(chunks);
+        self
+    }
+
+    /// Streams the body from `reader`, see [`Response::with_body_reader`]
+    pub fn with_body_reader<R>(&mut self, reader: R) -> &mut Self
+    where
+        R: Read + Send + 'static,
+    {
+        self.response.with_body_reader(reader);
+        self
+    }
+
+    /// Waits between the chunks of a chunked or streamed body
+    pub const fn with_chunk_delay(&mut self, delay: Duration) -> &mut Self {
+        self.response.with_chunk_delay(delay);
+        self
+    }
+
+    /// Waits before sending the response, see [`Response::with_delay`]
+    pub const fn with_delay(&mut self, delay: Duration) -> &mut Self {
+        self.response.with_delay(delay);
+        self
+    }
+
+    /// Waits a random time between `min` and `max` before sending the response
+    pub const fn with_delay_range(&mut self, min: Duration, max: Duration) -> &mut Self {
+        self.response.with_delay_range(min, max);
+        self
+    }
+
+    /// Spreads sending the body over `duration`, see [`Response::with_body_duration`]
+    pub const fn with_body_duration(&mut self, duration: Duration) -> &mut Self {
+        self.response.with_body_duration(duration);
+        self
+    }
+
+    /// Sends the body no faster than `bytes_per_second`
+    pub const fn with_throttle(&mut self, bytes_per_second: u64) -> &mut Self {
+        self.response.with_throttle(bytes_per_second);
+        self
+    }
+
+    /// Simulates a network failure instead of responding, see [`Fault`]
+    pub const fn with_fault(&mut self, fault: Fault) -> &mut Self {
+        self.response.with_fault(fault);
+        self
+    }
+
+    /// Adds a trailer, see [`Response::with_trailer`]
+    pub fn with_trailer<K, V>(&mut self, name: K, value: V) -> &mut Self
+    where
+        K: ToString,
+        V: ToString,
+    {
+        self.response.with_trailer(name, value);
+        self
+    }
+
+    /// Builds the response from each matched request, instead of returning a fixed one
+    ///
+    /// Takes precedence over the status, headers and body set on the mock
+    pub fn with_responder<F>(&mut self, responder: F) -> &mut Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.responder = Some(Responder(Arc::new(responder)));
+        self
+    }
+
+    /// Only match requests whose `name` header satisfies the given [`Matcher`]
+    ///
+    /// Header names are compared case-insensitively. Can be called multiple times, in which
+    /// case every header must match
+    ///
+    /// # Panics
+    /// If given a [`Matcher::Regex`] that isn't a valid regular expression
+    pub fn match_header<K>(&mut self, name: K, matcher: Matcher) -> &mut Self
+    where
+        K: ToString,
+    {
+        let matcher = matcher
+            .compile()
+            .unwrap_or_else(|err| panic!("Bad header matcher: {}", err));
+        self.headers
+            .push((name.to_string().to_ascii_lowercase(), matcher));
+        self
+    }
+
+    /// Only match requests whose path, without the query string, satisfies the given
+    /// [`Matcher`], instead of the path given to [`Mock::new`]
+    ///
+    /// The query string is then ignored, unless [`Mock::match_query`] is used
+    ///
+    /// # Panics
+    /// If given a [`Matcher::Regex`] that isn't a valid regular expression
+    pub fn match_path(&mut self, matcher: Matcher) -> &mut Self {
+        let matcher = matcher
+            .compile()
+            .unwrap_or_else(|err| panic!("Bad path matcher: {}", err));
+        self.path_matcher = Some(matcher);
+        self
+    }
+
+    /// Only match requests whose `name` query parameter satisfies the given [`Matcher`]
+    ///
+    /// Once called, the path no longer has to match the query string exactly: parameters
+    /// given in the path become [`Matcher::Exact`] ones, and others may be sent too. Can be
+    /// called multiple times, in which case every parameter must match
+    ///
+    /// # Panics
+    /// If given a [`Matcher::Regex`] that isn't a valid regular expression
+    pub fn match_query<K>(&mut self, name: K, matcher: Matcher) -> &mut Self
+    where
+        K: ToString,
+    {
+        let matcher = matcher
+            .compile()
+            .unwrap_or_else(|err| panic!("Bad query matcher: {}", err));
+        if let Some((path, query)) = self.path.split_once('?') {
+            self.query = url::form_urlencoded::parse(query.as_bytes())
+                .map(|(name, value)| {
+                    let matcher = Matcher::Exact(value.into_owned()).compile();
+                    (
+                        name.into_owned(),
+                        matcher.expect("exact matchers always compile"),
+                    )
+                })
+                .collect();
+            self.path = path.to_string();
+        }
+        self.query.push((name.to_string(), matcher));
+        self
+    }
+
+    /// Only match requests whose body satisfies the given [`Matcher`]
+    ///
+    /// Can be called multiple times, in which case every matcher must match
+    ///
+    /// # Panics
+    /// If given a [`Matcher::Regex`] that isn't a valid regular expression
+    pub fn match_body(&mut self, matcher: Matcher) -> &mut Self {
+        let matcher = matcher
+            .compile()
+            .unwrap_or_else(|err| panic!("Bad body matcher: {}", err));
+        self.body.push(matcher);
+        self
+    }
+
+    /// Sends each of the given responses in turn, one per matched request
+    ///
+    /// What happens after the last one is decided by [`Mock::when_sequence_exhausted`].
+    /// Takes precedence over the status, headers and body set on the mock
+    pub fn with_response_sequence(&mut self, responses: Vec<Response>) -> &mut Self {
+        self.sequence = responses;
+        self
+    }
+
+    /// Decides what happens once every response given to [`Mock::with_response_sequence`]
+    /// has been sent
+    ///
+    /// Default is [`Exhausted::RepeatLast`]
+    pub const fn when_sequence_exhausted(&mut self, exhausted: Exhausted) -> &mut Self {
+        self.exhausted = exhausted;
+        self
+    }
+
+    /// Expect the mock to be hit exactly `hits` times
+    ///
+    /// Checked by [`MockHandle::assert`] and [`Proxy::verify`](crate::Proxy::verify)
+    pub const fn expect(&mut self, hits: usize) -> &mut Self {
+        self.expected = Expectation {
+            at_least: hits,
+            at_most: Some(hits),
         };
         self
     }
 
+    /// Expect the mock to be hit at least `hits` times
+    pub const fn expect_at_least(&mut self, hits: usize) -> &mut Self {
+        self.expected.at_least = hits;
+        self
+    }
+
+    /// Expect the mock to be hit at most `hits` times
+    pub const fn expect_at_most(&mut self, hits: usize) -> &mut Self {
+        self.expected.at_most = Some(hits);
+        self
+    }
+
     /// Freezes the given [`Mock`]
     pub fn create(&self) -> Self {
         self.clone()
     }
 
+    /// Starts counting hits afresh and returns a handle to them
+    pub(super) fn register(&mut self, id: usize) -> MockHandle {
+        self.id = id;
+        self.hits = Arc::default();
+        MockHandle {
+            id,
+            description: self.to_string(),
+            expected: self.expected,
+            hits: Arc::clone(&self.hits),
+        }
+    }
+
+    /// Responds to `request` if it matches, counting it as a hit
+    ///
+    /// Returns `None` if it doesn't match, including when the mock has run out of responses
+    /// and [`Exhausted::FallThrough`] was chosen
+    pub(super) fn serve(&self, request: &Request) -> Option<Cow<'_, Response>> {
+        if !self.matches(request) {
+            return None;
+        }
+
+        // claimed atomically, as other connections may be racing for the last response
+        let hit = self
+            .hits
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |hits| {
+                (!self.is_exhausted(hits)).then_some(hits + 1)
+            })
+            .ok()?;
+
+        if let Some(responder) = &self.responder {
+            return Some(Cow::Owned((responder.0)(request)));
+        }
+        if self.sequence.is_empty() {
+            return Some(Cow::Borrowed(&self.response));
+        }
+
+        let len = self.sequence.len();
+        let response = match self.exhausted {
+            _ if hit < len => &self.sequence[hit],
+            Exhausted::Cycle => &self.sequence[hit % len],
+            Exhausted::Error => {
+                return Some(Cow::Owned(
+                    Response::new()
+                        .with_status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .with_body(format!("{} has no responses left", self))
+                        .create(),
+                ))
+            }
+            Exhausted::RepeatLast | Exhausted::FallThrough => &self.sequence[len - 1],
+        };
+        Some(Cow::Borrowed(response))
+    }
+
+    /// Whether this mock is for the host `request` opens a tunnel to
+    pub(super) fn is_for_host(&self, request: &Request) -> bool {
+        self.host.is_some() && self.host.as_deref() == request.host()
+    }
+
+    /// Whether the tunnel opened by `request` should be closed before the TLS handshake,
+    /// counting as a hit if so
+    ///
+    /// Only mocks for the host of the tunnel refuse it
+    pub(super) fn refuses_tls(&self, request: &Request) -> bool {
+        let refuses = self.response.fault == Some(Fault::CloseBeforeTlsHandshake)
+            && self.is_for_host(request);
+        if refuses {
+            self.hits.fetch_add(1, Ordering::SeqCst);
+        }
+        refuses
+    }
+
+    /// Whether the mock has run out of responses and should no longer match
+    fn is_exhausted(&self, hits: usize) -> bool {
+        self.exhausted == Exhausted::FallThrough
+            && self.responder.is_none()
+            && !self.sequence.is_empty()
+            && hits >= self.sequence.len()
+    }
+
     pub(super) fn matches(&self, request: &Request) -> bool {
-        let host_match = match &self.host {
-            Some(host) => {
-                host == request
-                    .host
-                    .as_ref()
-                    .expect("Expected request to have a host")
+        self.mismatches(request).is_empty()
+    }
+
+    /// Describes each way in which `request` fails to match this mock
+    pub(super) fn mismatches(&self, request: &Request) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        let mut compare = |what: &str, expected: &str, actual: &str| {
+            if expected != actual {
+                mismatches.push(Mismatch {
+                    weight: 2,
+                    description: format!("{}: expected {}, got {}", what, expected, actual),
+                });
             }
-            None => true,
         };
 
-        host_match
-            && &self.path == request.path.as_ref().unwrap()
-            && &self.method == request.method.as_ref().unwrap()
+        compare("method", &self.method, request.method());
+        if let Some(host) = &self.host {
+            compare("host", host, request.host().unwrap_or("none"));
+        }
+        if let Some(matcher) = &self.path_matcher {
+            if !matcher.matches_values(&[request.path()]) {
+                mismatches.push(Mismatch {
+                    weight: 2,
+                    description: format!("path: expected {}, got {}", matcher, request.path()),
+                });
+            }
+        } else {
+            let path = if self.query.is_empty() {
+                request.path.as_deref().unwrap_or_default()
+            } else {
+                request.path()
+            };
+            compare("path", &self.path, path);
+        }
+
+        for (name, matcher) in &self.headers {
+            let values = request.header_values(name);
+            if !matcher.matches_values(&values) {
+                let actual = if values.is_empty() {
+                    "absent".to_string()
+                } else {
+                    format!("{:?}", values)
+                };
+                mismatches.push(Mismatch {
+                    weight: 1,
+                    description: format!("header `{}`: expected {}, got {}", name, matcher, actual),
+                });
+            }
+        }
+
+        if !self.query.is_empty() {
+            let pairs = request.query_pairs();
+            for (name, matcher) in &self.query {
+                let values: Vec<&str> = pairs
+                    .iter()
+                    .filter(|(n, _)| n == name)
+                    .map(|(_, value)| value.as_str())
+                    .collect();
+                if !matcher.matches_values(&values) {
+                    let actual = if values.is_empty() {
+                        "absent".to_string()
+                    } else {
+                        format!("{:?}", values)
+                    };
+                    mismatches.push(Mismatch {
+                        weight: 1,
+                        description: format!(
+                            "query `{}`: expected {}, got {}",
+                            name, matcher, actual
+                        ),
+                    });
+                }
+            }
+        }
+
+        if self.is_exhausted(self.hits.load(Ordering::SeqCst)) {
+            mismatches.push(Mismatch {
+                weight: 1,
+                description: format!(
+                    "sequence: all {} response(s) have been sent",
+                    self.sequence.len()
+                ),
+            });
+        }
+
+        for matcher in &self.body {
+            if !matcher.matches_body(&request.body) {
+                mismatches.push(Mismatch {
+                    weight: 1,
+                    description: format!(
+                        "body: expected {}, got {}",
+                        matcher,
+                        describe_body(&request.body)
+                    ),
+                });
+            }
+        }
+
+        mismatches
+    }
+}
+
+/// One way in which a request differs from a [`Mock`]
+pub(super) struct Mismatch {
+    /// How far off this makes the request, so a wrong path outweighs a wrong header
+    pub(super) weight: usize,
+    pub(super) description: String,
+}
+
+fn describe_body(body: &[u8]) -> String {
+    const LIMIT: usize = 200;
+
+    if body.is_empty() {
+        return "an empty body".to_string();
+    }
+    let text = String::from_utf8_lossy(&body[..body.len().min(LIMIT)]);
+    if body.len() > LIMIT {
+        format!("{:?}... ({} bytes)", text, body.len())
+    } else {
+        format!("{:?}", text)
+    }
+}
+
+impl std::fmt::Display for Mock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            self.method,
+            self.host.as_deref().unwrap_or_default()
+        )?;
+        match &self.path_matcher {
+            Some(matcher) => write!(f, "{}", matcher),
+            None => write!(f, "{}", self.path),
+        }
     }
 }
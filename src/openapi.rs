@@ -0,0 +1,263 @@
+use crate::{Error, Matcher, Mock, Response};
+use json::JsonValue;
+use std::fs;
+use std::path::Path;
+
+/// Operations a path item may hold, by their key in the document
+const METHODS: [&str; 8] = [
+    "get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+
+/// How many `$ref`s are followed before giving up, as schemas may refer to themselves
+const MAX_REF_DEPTH: usize = 16;
+
+/// An operation read from the document, kept until every path is read so concrete paths can
+/// be tried before templated ones
+struct Operation {
+    parameters: usize,
+    mock: Mock,
+}
+
+/// Builds a [`Mock`] for every operation of the OpenAPI 3 document at `path`
+pub(crate) fn load(path: &Path) -> Result<Vec<Mock>, Error> {
+    let invalid = |message: String| Error::InvalidFile {
+        path: path.to_path_buf(),
+        message,
+    };
+    let text = fs::read_to_string(path).map_err(|source| Error::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let document = json::parse(&text).map_err(|err| invalid(err.to_string()))?;
+    if !document["openapi"]
+        .as_str()
+        .is_some_and(|version| version.starts_with("3."))
+    {
+        return Err(invalid("not an OpenAPI 3 document".to_string()));
+    }
+    if !document["paths"].is_object() {
+        return Err(invalid("missing `paths`".to_string()));
+    }
+
+    let (base, prefix) = server_of(&document["servers"][0]["url"]);
+    let mut operations = Vec::new();
+    for (template, item) in document["paths"].entries() {
+        let (matcher, parameters) = path_matcher(&format!("{}{}", prefix, template))
+            .map_err(|message| invalid(format!("path `{}`: {}", template, message)))?;
+        for method in METHODS {
+            let operation = &item[method];
+            if operation.is_null() {
+                continue;
+            }
+            let response = response_of(&document, &operation["responses"]).map_err(|message| {
+                invalid(format!(
+                    "{} {}: {}",
+                    method.to_uppercase(),
+                    template,
+                    message
+                ))
+            })?;
+            let mut mock = Mock::try_new(&method.to_uppercase(), &base)
+                .map_err(|err| invalid(err.to_string()))?;
+            mock.match_path(matcher.clone());
+            mock.response = response;
+            operations.push(Operation { parameters, mock });
+        }
+    }
+
+    // stable, so `/users/me` is tried before `/users/{id}`, each in document order
+    operations.sort_by_key(|operation| operation.parameters);
+    Ok(operations
+        .into_iter()
+        .map(|operation| operation.mock)
+        .collect())
+}
+
+/// Where the first server is, as the URL its mocks are created with and the prefix of their
+/// paths
+///
+/// Servers with variables in their URL are matched on any host
+fn server_of(url: &JsonValue) -> (String, String) {
+    let url = url.as_str().unwrap_or_default();
+    if url.contains('{') {
+        return ("/".to_string(), String::new());
+    }
+    match url::Url::parse(url) {
+        Ok(parsed) if parsed.has_host() => {
+            let base = format!(
+                "{}://{}/",
+                parsed.scheme(),
+                parsed.host_str().unwrap_or_default()
+            );
+            (base, parsed.path().trim_end_matches('/').to_string())
+        }
+        _ => ("/".to_string(), url.trim_end_matches('/').to_string()),
+    }
+}
+
+/// Matches the path template, with each `{parameter}` standing for any single segment
+///
+/// Returns how many parameters the template has
+fn path_matcher(template: &str) -> Result<(Matcher, usize), String> {
+    let mut pattern = String::from("^");
+    let mut parameters = 0;
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or("unclosed `{` in the template")?;
+        pattern.push_str(&regex::escape(&rest[..start]));
+        pattern.push_str("[^/]+");
+        parameters += 1;
+        rest = &rest[start + end + 1..];
+    }
+    if parameters == 0 {
+        return Ok((Matcher::Exact(template.to_string()), 0));
+    }
+    pattern.push_str(&regex::escape(rest));
+    pattern.push('$');
+    Ok((Matcher::Regex(pattern), parameters))
+}
+
+/// The response for the first success status, or an empty `200 OK` if none is documented
+fn response_of(document: &JsonValue, responses: &JsonValue) -> Result<Response, String> {
+    let mut built = Response::new();
+    let success = responses
+        .entries()
+        .filter_map(|(status, response)| {
+            let code = match status {
+                "2XX" | "2xx" => 200,
+                _ => status.parse::<u16>().ok()?,
+            };
+            (200..300).contains(&code).then_some((code, response))
+        })
+        .min_by_key(|(code, _)| *code);
+    let Some((status, response)) = success else {
+        return Ok(built);
+    };
+    built
+        .try_with_status(status)
+        .map_err(|err| err.to_string())?;
+
+    let response = resolve(document, response, 0)?;
+    let media = response["content"]
+        .entries()
+        .find(|(media_type, _)| is_json(media_type))
+        .or_else(|| response["content"].entries().next());
+    let Some((media_type, media)) = media else {
+        return Ok(built);
+    };
+    built.with_header("Content-Type", media_type);
+
+    let example = if media.has_key("example") {
+        media["example"].clone()
+    } else if let Some((_, example)) = media["examples"].entries().next() {
+        resolve(document, example, 0)?["value"].clone()
+    } else {
+        sample(document, &media["schema"], 0)?
+    };
+    match example.as_str() {
+        Some(text) if !is_json(media_type) => built.with_body(text),
+        _ => built.with_body_from_json(example),
+    };
+    Ok(built)
+}
+
+fn is_json(media_type: &str) -> bool {
+    let media_type = media_type.split(';').next().unwrap_or_default().trim();
+    media_type == "application/json" || media_type.ends_with("+json")
+}
+
+/// Builds a value satisfying `schema`, preferring any example, default or enum it gives
+fn sample(document: &JsonValue, schema: &JsonValue, depth: usize) -> Result<JsonValue, String> {
+    let schema = resolve(document, schema, depth)?;
+    let depth = depth + 1;
+    if depth > MAX_REF_DEPTH {
+        return Ok(JsonValue::Null);
+    }
+    for key in ["example", "default", "const"] {
+        if schema.has_key(key) {
+            return Ok(schema[key].clone());
+        }
+    }
+    if schema["enum"].is_array() && !schema["enum"].is_empty() {
+        return Ok(schema["enum"][0].clone());
+    }
+    if schema["allOf"].is_array() {
+        let mut merged = JsonValue::new_object();
+        for part in schema["allOf"].members() {
+            let part = sample(document, part, depth)?;
+            for (key, value) in part.entries() {
+                merged[key] = value.clone();
+            }
+        }
+        return Ok(merged);
+    }
+    for key in ["oneOf", "anyOf"] {
+        if schema[key].is_array() && !schema[key].is_empty() {
+            return sample(document, &schema[key][0], depth);
+        }
+    }
+
+    // OpenAPI 3.1 allows a list of types, such as `["string", "null"]`
+    let kind = schema["type"]
+        .as_str()
+        .or_else(|| schema["type"].members().find_map(JsonValue::as_str))
+        .unwrap_or_else(|| {
+            if schema.has_key("properties") {
+                "object"
+            } else {
+                ""
+            }
+        });
+    Ok(match kind {
+        "object" => {
+            let mut object = JsonValue::new_object();
+            for (name, property) in schema["properties"].entries() {
+                object[name] = sample(document, property, depth)?;
+            }
+            object
+        }
+        "array" => JsonValue::Array(vec![sample(document, &schema["items"], depth)?]),
+        "string" => match schema["format"].as_str() {
+            Some("date") => "1970-01-01",
+            Some("date-time") => "1970-01-01T00:00:00Z",
+            Some("uuid") => "00000000-0000-0000-0000-000000000000",
+            Some("email") => "user@example.com",
+            Some("uri" | "url") => "https://example.com",
+            _ => "string",
+        }
+        .into(),
+        "integer" | "number" => schema["minimum"]
+            .as_number()
+            .map_or_else(|| 0.into(), Into::into),
+        "boolean" => true.into(),
+        _ => JsonValue::Null,
+    })
+}
+
+/// Follows `value` to what it refers to, if it is a local `$ref`
+fn resolve<'a>(
+    document: &'a JsonValue,
+    value: &'a JsonValue,
+    depth: usize,
+) -> Result<&'a JsonValue, String> {
+    let Some(reference) = value["$ref"].as_str() else {
+        return Ok(value);
+    };
+    if depth > MAX_REF_DEPTH {
+        return Err(format!("too many nested references at `{}`", reference));
+    }
+    let pointer = reference
+        .strip_prefix("#/")
+        .ok_or_else(|| format!("unsupported reference `{}`, only local ones are", reference))?;
+    let mut target = document;
+    for token in pointer.split('/') {
+        let token = token.replace("~1", "/").replace("~0", "~");
+        target = &target[token.as_str()];
+    }
+    if target.is_null() {
+        return Err(format!("unresolved reference `{}`", reference));
+    }
+    resolve(document, target, depth + 1)
+}
@@ -0,0 +1,225 @@
+use crate::request::read_chunked_body;
+use crate::{Request, Response, Scheme};
+use http::StatusCode;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Headers that only concern a single connection, so aren't passed along
+const HOP_BY_HOP: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Which requests matching no [`Mock`](crate::Mock) are forwarded, instead of answered with
+/// an error
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Passthrough {
+    /// Answer every unmatched request with an error
+    #[default]
+    None,
+    /// Forward every unmatched request
+    All,
+    /// Forward unmatched requests to these hosts only
+    Hosts(Vec<String>),
+}
+
+impl Passthrough {
+    /// Forwards unmatched requests to the given hosts only
+    pub fn hosts<I, S>(hosts: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        Self::Hosts(hosts.into_iter().map(|host| host.to_string()).collect())
+    }
+
+    pub(crate) fn allows(&self, host: Option<&str>) -> bool {
+        match self {
+            Self::None => false,
+            Self::All => true,
+            Self::Hosts(hosts) => {
+                host.is_some_and(|host| hosts.iter().any(|h| h.eq_ignore_ascii_case(host)))
+            }
+        }
+    }
+}
+
+/// Sends `request` on to `upstream` if given, otherwise to the host it was meant for
+///
+/// Connecting, and each read or write after, gives up after `timeout`
+pub(crate) fn forward(
+    request: &Request,
+    scheme: Scheme,
+    upstream: Option<SocketAddr>,
+    timeout: Duration,
+) -> Result<Response, Box<dyn std::error::Error>> {
+    let host = request.host().ok_or("Request has no host to forward to")?;
+    if let Some(upstream) = upstream {
+        let stream = connect(&[upstream], timeout)?;
+        return exchange(&mut &stream, request, host);
+    }
+
+    let port = request.port.unwrap_or(match scheme {
+        Scheme::Http => 80,
+        Scheme::Https => 443,
+    });
+    let addresses: Vec<SocketAddr> = (host, port).to_socket_addrs()?.collect();
+    let stream = connect(&addresses, timeout)?;
+    match scheme {
+        Scheme::Http => exchange(&mut &stream, request, host),
+        Scheme::Https => {
+            let connector = native_tls::TlsConnector::new()?;
+            let mut stream = connector.connect(host, stream)?;
+            exchange(&mut stream, request, host)
+        }
+    }
+}
+
+/// Whether forwarding failed because the upstream took too long
+pub(crate) fn is_timeout(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Connects to the first of `addresses` that answers within `timeout`
+fn connect(addresses: &[SocketAddr], timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for address in addresses {
+        match TcpStream::connect_timeout(address, timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(err) => last_error = Some(err),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(ErrorKind::NotFound, "Host has no addresses")))
+}
+
+/// Writes `request` to `stream` and reads back the whole response
+///
+/// The target and `Host` header are passed on as received, so the upstream sees the same
+/// query string and port
+fn exchange<S: Read + Write>(
+    stream: &mut S,
+    request: &Request,
+    host: &str,
+) -> Result<Response, Box<dyn std::error::Error>> {
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\n",
+        request.method(),
+        request.origin_target(),
+        request.authority().as_deref().unwrap_or(host)
+    )?;
+    for (name, value) in request.headers() {
+        let skip = name.eq_ignore_ascii_case("host")
+            || name.eq_ignore_ascii_case("content-length")
+            || HOP_BY_HOP.iter().any(|h| name.eq_ignore_ascii_case(h));
+        if !skip {
+            write!(stream, "{}: {}\r\n", name, value)?;
+        }
+    }
+    if !request.body().is_empty() {
+        write!(stream, "Content-Length: {}\r\n", request.body().len())?;
+    }
+    stream.write_all(b"Connection: close\r\n\r\n")?;
+    stream.write_all(request.body())?;
+    stream.flush()?;
+
+    read_response(stream, request.method() == "HEAD")
+}
+
+fn read_response(
+    stream: &mut dyn Read,
+    head_only: bool,
+) -> Result<Response, Box<dyn std::error::Error>> {
+    let mut all_buf = Vec::new();
+    let mut buf = [0; 1024];
+    let (status, headers, head_length) = loop {
+        let read = stream.read(&mut buf)?;
+        if read == 0 {
+            return Err("Upstream closed the connection before responding".into());
+        }
+        all_buf.extend_from_slice(&buf[..read]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 100];
+        let mut response = httparse::Response::new(&mut headers);
+        if let httparse::Status::Complete(head_length) = response.parse(&all_buf)? {
+            let headers: Vec<(String, String)> = response
+                .headers
+                .iter()
+                .map(|header| {
+                    (
+                        header.name.to_string(),
+                        String::from_utf8_lossy(header.value).into_owned(),
+                    )
+                })
+                .collect();
+            break (response.code.unwrap_or(502), headers, head_length);
+        }
+    };
+
+    let status = StatusCode::from_u16(status)?;
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    };
+    let bodyless = head_only
+        || status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED;
+
+    let mut reader = std::io::Cursor::new(all_buf[head_length..].to_vec()).chain(stream);
+    let body = if bodyless {
+        Vec::new()
+    } else if header("transfer-encoding").is_some_and(|value| value.contains("chunked")) {
+        read_chunked_body(&mut reader, usize::MAX)?
+    } else if let Some(length) = header("content-length") {
+        let length: u64 = length.trim().parse()?;
+        let mut body = Vec::new();
+        if (&mut reader).take(length).read_to_end(&mut body)? as u64 != length {
+            return Err("Upstream closed the connection before the end of the body".into());
+        }
+        body
+    } else {
+        // ended by the connection closing, which some servers do without a TLS close_notify
+        let mut body = Vec::new();
+        if let Err(err) = reader.read_to_end(&mut body) {
+            if body.is_empty() {
+                return Err(err.into());
+            }
+        }
+        body
+    };
+
+    let mut response = Response::new();
+    response.status = status;
+    response.headers = headers
+        .into_iter()
+        .filter(|(name, _)| {
+            // a HEAD response has no body, but still reports the length of one
+            (head_only || !name.eq_ignore_ascii_case("content-length"))
+                && !HOP_BY_HOP.iter().any(|h| name.eq_ignore_ascii_case(h))
+        })
+        .collect();
+    response.body = body;
+    Ok(response)
+}
@@ -0,0 +1,107 @@
+use log::error;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Worker threads that connections are handed off to
+///
+/// A fixed number of workers is kept, and a job arriving while all of them are busy gets a
+/// thread of its own, so connections kept alive while idle never hold up new ones
+pub(crate) struct ThreadPool {
+    workers: Vec<JoinHandle<()>>,
+    /// Threads spawned for jobs no worker was free for, which end with their job
+    overflow: Vec<JoinHandle<()>>,
+    sender: Sender<Job>,
+    /// How many workers are waiting for a job
+    idle: Arc<AtomicUsize>,
+}
+
+impl ThreadPool {
+    /// Spawns `size` workers, or a single one if `size` is zero
+    pub(crate) fn new(size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let size = size.max(1);
+        let idle = Arc::new(AtomicUsize::new(size));
+
+        let workers = (0..size)
+            .map(|id| {
+                let receiver = Arc::clone(&receiver);
+                let idle = Arc::clone(&idle);
+                thread::Builder::new()
+                    .name(format!("mock_proxy-worker-{}", id))
+                    .spawn(move || work(&receiver, &idle))
+                    .expect("Failed to spawn worker thread")
+            })
+            .collect();
+
+        Self {
+            workers,
+            overflow: Vec::new(),
+            sender,
+            idle,
+        }
+    }
+
+    /// Runs a job on a free worker, or on a thread of its own if every worker is busy
+    pub(crate) fn execute<F>(&mut self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // claims a free worker, so two jobs can't both count on the same one
+        let claimed = self
+            .idle
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |idle| {
+                idle.checked_sub(1)
+            })
+            .is_ok();
+        if claimed {
+            if self.sender.send(Box::new(job)).is_err() {
+                error!("All workers have exited, dropping job");
+            }
+            return;
+        }
+
+        self.overflow.retain(|thread| !thread.is_finished());
+        match thread::Builder::new()
+            .name(format!("mock_proxy-overflow-{}", self.overflow.len()))
+            .spawn(move || run(Box::new(job)))
+        {
+            Ok(thread) => self.overflow.push(thread),
+            Err(err) => error!("Failed to spawn thread, dropping job: {}", err),
+        }
+    }
+
+    /// Waits for every queued job to finish, then shuts the workers down
+    pub(crate) fn join(self) {
+        drop(self.sender);
+        for worker in self.workers.into_iter().chain(self.overflow) {
+            if worker.join().is_err() {
+                error!("Worker thread panicked");
+            }
+        }
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<Job>>, idle: &AtomicUsize) {
+    loop {
+        let job = receiver.lock().unwrap().recv();
+        let job = match job {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        run(job);
+        idle.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn run(job: Job) {
+    // a panicking connection shouldn't take the worker down with it
+    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+        error!("Connection handler panicked");
+    }
+}
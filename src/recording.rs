@@ -0,0 +1,197 @@
+use crate::{Error, Matcher, Mock, Request, Response, Scheme};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Longest slug of the request kept in fixture file names
+const MAX_SLUG_LENGTH: usize = 60;
+
+/// Writes every forwarded exchange to a directory of fixture files
+///
+/// Each exchange gets a JSON file describing the request and response, next to a file
+/// holding the response body, and one holding the request body if there was one
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    dir: PathBuf,
+    next: AtomicUsize,
+}
+
+impl Recorder {
+    /// Creates `dir` if needed, numbering new fixtures after any already in it
+    pub(crate) fn new(dir: &Path) -> Result<Self, Error> {
+        let io_error = |source| Error::Io {
+            path: dir.to_path_buf(),
+            source,
+        };
+        fs::create_dir_all(dir).map_err(io_error)?;
+        let existing = fixtures(dir)?.len();
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            next: AtomicUsize::new(existing + 1),
+        })
+    }
+
+    /// Saves one exchange, returning the path of its JSON file
+    pub(crate) fn save(
+        &self,
+        request: &Request,
+        scheme: Scheme,
+        response: &Response,
+    ) -> Result<PathBuf, Error> {
+        let number = self.next.fetch_add(1, Ordering::SeqCst);
+        let url = request.url(scheme);
+        let name = format!("{:04}-{}", number, slug(request.method(), &url));
+        let write = |file: &str, contents: &[u8]| {
+            let path = self.dir.join(file);
+            fs::write(&path, contents).map_err(|source| Error::Io { path, source })
+        };
+
+        let mut fixture = json::object! {
+            request: { method: request.method(), url: url.as_str() },
+            response: {
+                status: response.status.as_u16(),
+                headers: response
+                    .headers
+                    .iter()
+                    .map(|(name, value)| json::array![name.as_str(), value.as_str()])
+                    .collect::<Vec<_>>(),
+                body_file: format!("{}.body", name),
+            },
+        };
+        if !request.body().is_empty() {
+            let file = format!("{}.request", name);
+            write(&file, request.body())?;
+            fixture["request"]["body_file"] = file.into();
+        }
+        write(&format!("{}.body", name), &response.body)?;
+
+        let file = format!("{}.json", name);
+        write(&file, json::stringify_pretty(fixture, 2).as_bytes())?;
+        Ok(self.dir.join(file))
+    }
+}
+
+/// A distinct request read back from the fixtures, with every response recorded for it
+struct Recorded {
+    method: String,
+    url: String,
+    body: Option<Vec<u8>>,
+    mock: Mock,
+    responses: Vec<Response>,
+}
+
+/// Builds one [`Mock`] per distinct request recorded in `dir`, in the order they were recorded
+///
+/// A request recorded more than once replays its responses in turn
+pub(crate) fn load(dir: &Path) -> Result<Vec<Mock>, Error> {
+    let mut recorded: Vec<Recorded> = Vec::new();
+
+    for path in fixtures(dir)? {
+        let invalid = |message: &str| Error::InvalidFile {
+            path: path.clone(),
+            message: message.to_string(),
+        };
+        let read = |file: &str| {
+            let path = dir.join(file);
+            fs::read(&path).map_err(|source| Error::Io { path, source })
+        };
+
+        let text = fs::read_to_string(&path).map_err(|source| Error::Io {
+            path: path.clone(),
+            source,
+        })?;
+        let fixture = json::parse(&text).map_err(|err| invalid(&err.to_string()))?;
+        let request = &fixture["request"];
+        let method = request["method"]
+            .as_str()
+            .ok_or_else(|| invalid("missing request method"))?;
+        let url = request["url"]
+            .as_str()
+            .ok_or_else(|| invalid("missing request url"))?;
+        let body = request["body_file"].as_str().map(read).transpose()?;
+
+        let mut response = Response::new();
+        let status = fixture["response"]["status"]
+            .as_u16()
+            .ok_or_else(|| invalid("missing response status"))?;
+        response
+            .try_with_status(status)
+            .map_err(|err| invalid(&err.to_string()))?;
+        for header in fixture["response"]["headers"].members() {
+            match (header[0].as_str(), header[1].as_str()) {
+                (Some(name), Some(value)) => response.with_header(name, value),
+                _ => return Err(invalid("headers should be [name, value] pairs")),
+            };
+        }
+        if let Some(file) = fixture["response"]["body_file"].as_str() {
+            response.with_body(read(file)?);
+        }
+
+        let same = recorded
+            .iter_mut()
+            .find(|r| r.method == method && r.url == url && r.body == body);
+        match same {
+            Some(same) => same.responses.push(response),
+            None => {
+                let mut mock =
+                    Mock::try_new(method, url).map_err(|err| invalid(&err.to_string()))?;
+                if let Some(body) = &body {
+                    mock.match_body(Matcher::Bytes(body.clone()));
+                }
+                recorded.push(Recorded {
+                    method: method.to_string(),
+                    url: url.to_string(),
+                    body,
+                    mock,
+                    responses: vec![response],
+                });
+            }
+        }
+    }
+
+    Ok(recorded
+        .into_iter()
+        .map(|mut recorded| {
+            recorded.mock.with_response_sequence(recorded.responses);
+            recorded.mock
+        })
+        .collect())
+}
+
+/// The JSON fixture files in `dir`, sorted by name
+fn fixtures(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let io_error = |source| Error::Io {
+        path: dir.to_path_buf(),
+        source,
+    };
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).map_err(io_error)? {
+        let path = entry.map_err(io_error)?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// A file name friendly summary of a request
+fn slug(method: &str, url: &str) -> String {
+    let url = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let mut slug = format!("{}-{}", method, url)
+        .to_ascii_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect::<String>();
+    slug.truncate(MAX_SLUG_LENGTH);
+    slug.trim_end_matches('-').to_string()
+}
@@ -0,0 +1,445 @@
+use crate::mock::split_url;
+use crate::Scheme;
+use http::StatusCode;
+use std::io::Read;
+
+const HEAD_TOO_LARGE: StatusCode = StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE;
+
+/// How large a request may be before it is rejected
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Limits {
+    /// Bytes up to and including the blank line ending the head
+    pub(crate) max_head_size: usize,
+    pub(crate) max_headers: usize,
+    /// Bytes of the body, once any chunked encoding is removed
+    pub(crate) max_body_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_head_size: 64 * 1024,
+            max_headers: 100,
+            max_body_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// A body longer than allowed, whether announced by its `Content-Length` or its chunks
+#[derive(Debug)]
+pub(crate) struct BodyTooLarge;
+
+impl std::fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Body too large")
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// A request received by the [`Proxy`](crate::Proxy)
+///
+/// For requests tunnelled through `CONNECT`, the host is the one the tunnel was opened to
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub(crate) error: Option<String>,
+    /// Status to answer with when `error` is set
+    pub(crate) error_status: StatusCode,
+    pub(crate) host: Option<String>,
+    /// Port given explicitly in the request, or by the `CONNECT` target
+    pub(crate) port: Option<u16>,
+    /// Path including the query string, as matched against [`Mock`](crate::Mock)s
+    pub(crate) path: Option<String>,
+    /// The request target exactly as received, as forwarded upstream
+    pub(crate) target: Option<String>,
+    pub(crate) method: Option<String>,
+    pub(crate) version: (u8, u8),
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+    /// The connection ended before any part of a request was received
+    pub(crate) closed: bool,
+}
+
+impl std::fmt::Display for Request {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        f.debug_struct("Request")
+            .field("method", &self.method)
+            .field("host", &self.host)
+            .field("path", &self.path)
+            .finish()
+    }
+}
+impl Request {
+    /// The HTTP method, such as `GET`
+    pub fn method(&self) -> &str {
+        self.method.as_deref().unwrap_or_default()
+    }
+
+    /// The host the request was sent to, if known
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    /// The path, without the query string
+    pub fn path(&self) -> &str {
+        let path = self.path.as_deref().unwrap_or_default();
+        path.split('?').next().unwrap_or_default()
+    }
+
+    /// The decoded query string parameters, in the order they were sent
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        let path = self.path.as_deref().unwrap_or_default();
+        path.split_once('?')
+            .map(|(_, query)| {
+                url::form_urlencoded::parse(query.as_bytes())
+                    .into_owned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The first value of the given header, compared case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.header_values(name).into_iter().next()
+    }
+
+    /// Every header, in the order they were sent
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// The request body, empty if there wasn't one
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// The URL the request was originally sent to, including its scheme
+    pub(crate) fn url(&self, scheme: Scheme) -> String {
+        let (scheme, default_port) = match scheme {
+            Scheme::Http => ("http", 80),
+            Scheme::Https => ("https", 443),
+        };
+        let port = self
+            .port
+            .filter(|port| *port != default_port)
+            .map(|port| format!(":{}", port))
+            .unwrap_or_default();
+        let host = match self.host() {
+            Some(host) if host.contains(':') => format!("[{}]", host),
+            host => host.unwrap_or("localhost").to_string(),
+        };
+        format!(
+            "{}://{}{}{}",
+            scheme,
+            host,
+            port,
+            self.path.as_deref().unwrap_or("/")
+        )
+    }
+
+    /// The path and query as received, without the scheme and host of an absolute target
+    pub(crate) fn origin_target(&self) -> String {
+        let Some(target) = self.target.as_deref() else {
+            return self.path.clone().unwrap_or_else(|| "/".to_string());
+        };
+        let target = match target.split_once("://") {
+            Some((_, rest)) => rest.find(['/', '?']).map_or("", |start| &rest[start..]),
+            None => target,
+        };
+        let target = target.split('#').next().unwrap_or_default();
+        if target.starts_with('/') {
+            target.to_string()
+        } else {
+            format!("/{}", target)
+        }
+    }
+
+    /// The `Host` header as received, or the host and any port the request was sent to
+    pub(crate) fn authority(&self) -> Option<String> {
+        if let Some(host) = self.header("host") {
+            return Some(host.to_string());
+        }
+        let host = match self.host()? {
+            host if host.contains(':') => format!("[{}]", host),
+            host => host.to_string(),
+        };
+        Some(match self.port {
+            Some(port) => format!("{}:{}", host, port),
+            None => host,
+        })
+    }
+
+    pub(crate) const fn is_ok(&self) -> bool {
+        self.error().is_none()
+    }
+    pub(crate) const fn error(&self) -> Option<&String> {
+        self.error.as_ref()
+    }
+
+    /// Whether the connection should stay open once this request has been answered
+    ///
+    /// HTTP/1.1 connections persist unless closed explicitly, HTTP/1.0 ones only on request
+    pub(crate) fn keep_alive(&self) -> bool {
+        let connection = self.header_values("connection");
+        let has = |token: &str| {
+            connection.iter().any(|value| {
+                value
+                    .split(',')
+                    .any(|part| part.trim().eq_ignore_ascii_case(token))
+            })
+        };
+
+        if self.version.1 == 0 {
+            has("keep-alive")
+        } else {
+            !has("close")
+        }
+    }
+
+    /// All values of the given header, compared case-insensitively
+    pub(crate) fn header_values(&self, name: &str) -> Vec<&str> {
+        self.headers
+            .iter()
+            .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    /// Reads a request, head and body, failing if the head is larger than `limits` allow
+    ///
+    /// `pending` holds whatever was read past the end of the previous request on the
+    /// connection, and is left holding whatever is read past the end of this one, so
+    /// pipelined requests aren't lost
+    pub(crate) fn from(stream: &mut dyn Read, pending: &mut Vec<u8>, limits: Limits) -> Self {
+        let mut request = Self {
+            error: None,
+            error_status: StatusCode::BAD_REQUEST,
+            host: None,
+            port: None,
+            path: None,
+            target: None,
+            method: None,
+            version: (0, 0),
+            headers: Vec::new(),
+            body: Vec::new(),
+            closed: false,
+        };
+
+        let mut all_buf = std::mem::take(pending);
+        let head_length = loop {
+            if !all_buf.is_empty() {
+                match request.parse_head(&all_buf, limits.max_headers) {
+                    Ok(Some((head_length, _))) if head_length > limits.max_head_size => {
+                        return request.fail(HEAD_TOO_LARGE, "Request head too large");
+                    }
+                    Ok(Some((head_length, target))) => {
+                        if let Err(err) = request.parse_target(&target) {
+                            return request.fail(StatusCode::BAD_REQUEST, err);
+                        }
+                        break head_length;
+                    }
+                    Ok(None) if all_buf.len() > limits.max_head_size => {
+                        return request.fail(HEAD_TOO_LARGE, "Request head too large");
+                    }
+                    Ok(None) => {}
+                    Err(httparse::Error::TooManyHeaders) => {
+                        return request.fail(HEAD_TOO_LARGE, "Too many request headers");
+                    }
+                    Err(err) => return request.fail(StatusCode::BAD_REQUEST, err),
+                }
+            }
+
+            let mut buf = [0; 1024];
+            match stream.read(&mut buf) {
+                Ok(0) if all_buf.is_empty() => {
+                    request.closed = true;
+                    return request.fail(StatusCode::BAD_REQUEST, "Nothing to read.");
+                }
+                Ok(0) => return request.fail(StatusCode::BAD_REQUEST, "Incomplete request"),
+                Ok(rlen) => all_buf.extend_from_slice(&buf[..rlen]),
+                Err(err) => {
+                    request.closed = all_buf.is_empty();
+                    return request.fail(StatusCode::BAD_REQUEST, err);
+                }
+            }
+        };
+
+        *pending = all_buf.split_off(head_length);
+        match read_body(stream, pending, &request, limits.max_body_size) {
+            Ok(body) => request.body = body,
+            Err(err) if err.is::<BodyTooLarge>() => {
+                return request.fail(StatusCode::PAYLOAD_TOO_LARGE, err);
+            }
+            Err(err) => return request.fail(StatusCode::BAD_REQUEST, err),
+        }
+
+        request
+    }
+
+    /// Parses the head once `buf` holds all of it, returning its length and request target
+    fn parse_head(
+        &mut self,
+        buf: &[u8],
+        max_headers: usize,
+    ) -> Result<Option<(usize, String)>, httparse::Error> {
+        let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
+        let mut req = httparse::Request::new(&mut headers);
+
+        let head_length = match req.parse(buf)? {
+            httparse::Status::Complete(head_length) => head_length,
+            httparse::Status::Partial => return Ok(None),
+        };
+
+        self.method = req.method.map(|s| s.to_string());
+
+        if let Some(a @ 0..=1) = req.version {
+            self.version = (1, a);
+        }
+
+        self.headers = req
+            .headers
+            .iter()
+            .map(|header| {
+                (
+                    header.name.to_string(),
+                    String::from_utf8_lossy(header.value).into_owned(),
+                )
+            })
+            .collect();
+
+        let target = req.path.unwrap_or_default().to_string();
+        Ok(Some((head_length, target)))
+    }
+
+    /// Takes the host, port and path from the request target, such as `host:443` for a
+    /// `CONNECT` or `http://host/path` for a plain HTTP request
+    fn parse_target(&mut self, target: &str) -> Result<(), String> {
+        self.target = Some(target.to_string());
+        if self.method() == "CONNECT" {
+            let (host, port) = match target.rsplit_once(':') {
+                // the colons of a bracketed IPv6 address without a port
+                Some((_, port)) if port.ends_with(']') => (target, None),
+                Some((host, port)) => (host, Some(port)),
+                None => (target, None),
+            };
+            let host = host
+                .strip_prefix('[')
+                .and_then(|host| host.strip_suffix(']'))
+                .unwrap_or(host);
+            if host.is_empty() || host.contains(['[', ']']) {
+                return Err(format!("Bad CONNECT target {:?}", target));
+            }
+            self.host = Some(host.to_string());
+            self.port = port
+                .map(|port| port.parse())
+                .transpose()
+                .map_err(|_| format!("Bad port in CONNECT target {:?}", target))?;
+        } else {
+            let (host, path) = split_url(target)
+                .map_err(|err| format!("Bad request target {:?}: {}", target, err))?;
+            self.host = host;
+            self.port = url::Url::parse(target).ok().and_then(|url| url.port());
+            self.path = Some(path);
+        }
+        Ok(())
+    }
+
+    fn fail<E: ToString>(mut self, status: StatusCode, error: E) -> Self {
+        self.error_status = status;
+        self.error = Some(error.to_string());
+        self
+    }
+}
+
+/// Reads the body of a request whose head has already been parsed, up to `max_size` bytes
+///
+/// `pending` holds whatever was read past the end of the head, and is left holding whatever
+/// follows the body
+fn read_body(
+    stream: &mut dyn Read,
+    pending: &mut Vec<u8>,
+    request: &Request,
+    max_size: usize,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut reader = std::io::Cursor::new(std::mem::take(pending)).chain(stream);
+    let body = body_of(&mut reader, request, max_size);
+    let (unread, _) = reader.into_inner();
+    let position = unread.position() as usize;
+    *pending = unread.into_inner().split_off(position);
+    body
+}
+
+fn body_of(
+    reader: &mut dyn Read,
+    request: &Request,
+    max_size: usize,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let chunked = request
+        .header_values("transfer-encoding")
+        .iter()
+        .any(|value| value.to_ascii_lowercase().contains("chunked"));
+    if chunked {
+        return read_chunked_body(reader, max_size);
+    }
+
+    let content_length = match request.header_values("content-length").first() {
+        Some(value) => value.trim().parse::<usize>()?,
+        None => return Ok(Vec::new()),
+    };
+    if content_length > max_size {
+        return Err(BodyTooLarge.into());
+    }
+    let mut body = Vec::new();
+    read_exactly(reader, content_length, &mut body)?;
+    Ok(body)
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body of up to `max_size` bytes, discarding any
+/// trailers
+pub(crate) fn read_chunked_body(
+    reader: &mut dyn Read,
+    max_size: usize,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)?;
+        if size == 0 {
+            while !read_line(reader)?.is_empty() {}
+            return Ok(body);
+        }
+
+        if !body
+            .len()
+            .checked_add(size)
+            .is_some_and(|length| length <= max_size)
+        {
+            return Err(BodyTooLarge.into());
+        }
+        read_exactly(reader, size, &mut body)?;
+        read_line(reader)?;
+    }
+}
+
+/// Appends `length` bytes to `buf`, growing it only as they arrive rather than trusting
+/// `length` up front
+fn read_exactly(reader: &mut dyn Read, length: usize, buf: &mut Vec<u8>) -> std::io::Result<()> {
+    let read = reader.take(length as u64).read_to_end(buf)?;
+    if read < length {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Reads a single CRLF terminated line, without reading past it
+fn read_line(reader: &mut dyn Read) -> Result<String, Box<dyn std::error::Error>> {
+    let mut line = Vec::new();
+    let mut byte = [0; 1];
+    while !line.ends_with(b"\r\n") {
+        reader.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    line.truncate(line.len() - 2);
+    Ok(String::from_utf8(line)?)
+}
@@ -0,0 +1,121 @@
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Size of the chunks read from a body reader
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+/// A response body sent one piece at a time, usually with `Transfer-Encoding: chunked`
+#[derive(Debug, Clone)]
+pub(crate) enum StreamedBody {
+    Chunks(Vec<Vec<u8>>),
+    Reader(Arc<Mutex<ReaderState>>),
+}
+
+/// A reader shared by every response of a mock
+///
+/// The first response reads from it as it goes, later ones replay what was read
+pub(crate) struct ReaderState {
+    reader: Option<Box<dyn Read + Send>>,
+    chunks: Vec<Vec<u8>>,
+}
+
+impl std::fmt::Debug for ReaderState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReaderState")
+            .field("finished", &self.reader.is_none())
+            .field("chunks", &self.chunks.len())
+            .finish()
+    }
+}
+
+impl StreamedBody {
+    pub(crate) fn from_reader(reader: Box<dyn Read + Send>) -> Self {
+        Self::Reader(Arc::new(Mutex::new(ReaderState {
+            reader: Some(reader),
+            chunks: Vec::new(),
+        })))
+    }
+
+    /// Returns the chunk at `index`, or `None` once the body is finished
+    fn chunk(&self, index: usize) -> std::io::Result<Option<Vec<u8>>> {
+        match self {
+            Self::Chunks(chunks) => Ok(chunks.get(index).cloned()),
+            Self::Reader(state) => {
+                let mut state = state.lock().unwrap();
+                if let Some(chunk) = state.chunks.get(index) {
+                    return Ok(Some(chunk.clone()));
+                }
+                let reader = match state.reader.as_mut() {
+                    Some(reader) => reader,
+                    None => return Ok(None),
+                };
+
+                let mut chunk = vec![0; READ_CHUNK_SIZE];
+                let read = reader.read(&mut chunk)?;
+                if read == 0 {
+                    state.reader = None;
+                    return Ok(None);
+                }
+                chunk.truncate(read);
+                state.chunks.push(chunk.clone());
+                drop(state);
+                Ok(Some(chunk))
+            }
+        }
+    }
+
+    /// Every byte of the body known so far
+    ///
+    /// For a reader, that's only what was already read while sending a response
+    pub(crate) fn collected(&self) -> Vec<u8> {
+        match self {
+            Self::Chunks(chunks) => chunks.concat(),
+            Self::Reader(state) => state.lock().unwrap().chunks.concat(),
+        }
+    }
+
+    /// Writes the body in chunked encoding, waiting `delay` between chunks and flushing
+    /// after every one
+    ///
+    /// With `chunked` unset the chunks are written as they are, for clients that rely on
+    /// the connection being closed instead, and `trailers` are dropped
+    pub(crate) fn write_to(
+        &self,
+        stream: &mut dyn Write,
+        chunked: bool,
+        delay: Duration,
+        trailers: &[(String, String)],
+    ) -> std::io::Result<()> {
+        let mut index = 0;
+        while let Some(chunk) = self.chunk(index)? {
+            if index > 0 && !delay.is_zero() {
+                thread::sleep(delay);
+            }
+            index += 1;
+            // an empty chunk would end the body early
+            if chunk.is_empty() {
+                continue;
+            }
+
+            if chunked {
+                write!(stream, "{:x}\r\n", chunk.len())?;
+                stream.write_all(&chunk)?;
+                stream.write_all(b"\r\n")?;
+            } else {
+                stream.write_all(&chunk)?;
+            }
+            stream.flush()?;
+        }
+
+        if chunked {
+            stream.write_all(b"0\r\n")?;
+            for (name, value) in trailers {
+                write!(stream, "{}: {}\r\n", name, value)?;
+            }
+            stream.write_all(b"\r\n")?;
+        }
+        stream.flush()
+    }
+}
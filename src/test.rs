@@ -1,4 +1,4 @@
-use crate::{Mock, Proxy};
+use crate::{Error, Exhausted, Fault, Matcher, Mock, Passthrough, Proxy, Response, Scheme};
 use log::warn;
 use simple_logger::SimpleLogger;
 use std::ops::Index;
@@ -7,7 +7,7 @@ fn build_client(proxy: &Proxy) -> reqwest::Client {
     let certificate = reqwest::Certificate::from_pem(&proxy.get_certificate()).unwrap();
     let client = reqwest::ClientBuilder::new()
         .add_root_certificate(certificate)
-        .proxy(reqwest::Proxy::all(&proxy.url()).unwrap())
+        .proxy(reqwest::Proxy::all(proxy.url()).unwrap())
         .build()
         .unwrap();
     warn!("Client created");
@@ -91,7 +91,7 @@ async fn test_errors() {
 
     let response = response.text().await.unwrap();
 
-    assert_eq!(response, "No matching response\r\n");
+    assert_eq!(response, "No matching response");
 }
 
 #[tokio::test]
@@ -133,3 +133,1746 @@ async fn test_multiple_https() {
         assert_eq!(response.status(), 200);
     }
 }
+
+#[tokio::test]
+async fn test_stop_and_restart() {
+    let mut proxy = Proxy::default();
+    proxy.register(Mock::new("GET", "https://localhost/hello").create());
+    proxy.start();
+
+    let address = proxy.address();
+    let certificate = proxy.get_certificate();
+    proxy.stop();
+
+    std::net::TcpListener::bind(address).expect("port should be released after stop");
+
+    proxy.start();
+    assert_eq!(proxy.get_certificate(), certificate);
+
+    let client = build_client(&proxy);
+    let response = client.get("https://localhost/hello").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    proxy.stop();
+    proxy.stop();
+}
+
+#[tokio::test]
+async fn test_builder_ipv6_ephemeral_port() {
+    let mut proxy = Proxy::builder()
+        .with_bind_address(std::net::Ipv6Addr::LOCALHOST.into())
+        .with_ephemeral_port()
+        .build();
+    proxy.register(Mock::new("GET", "http://localhost/hello").create());
+    proxy.start();
+
+    let address = proxy.address();
+    assert!(address.is_ipv6());
+    assert_ne!(address.port(), 1234);
+
+    let client = build_client(&proxy);
+    let response = client.get("http://localhost/hello").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+#[test]
+fn test_builder_exact_port() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let mut proxy = Proxy::builder().with_port(port).build();
+    proxy.start();
+    assert_eq!(proxy.address().port(), port);
+
+    let mut taken = Proxy::builder().with_port(port).build();
+    match taken.try_start() {
+        Err(Error::Bind { address, .. }) => assert_eq!(address.port(), port),
+        other => panic!("expected a bind error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_fallible_api() {
+    let mut proxy = Proxy::try_new().unwrap();
+    proxy.try_start().unwrap();
+    assert!(matches!(proxy.try_start(), Err(Error::AlreadyStarted)));
+
+    let err = Mock::new("GET", "/").try_with_status(1000).unwrap_err();
+    assert!(matches!(err, Error::InvalidStatus(_)));
+    let err = Mock::try_new("GET", "https://[::1/").unwrap_err();
+    assert!(matches!(err, Error::InvalidUrl(_)));
+    assert!(Response::new().try_with_status(404).is_ok());
+}
+
+#[test]
+fn test_incomplete_request() {
+    use std::io::Write;
+
+    let mut proxy = Proxy::default();
+    proxy.start();
+
+    let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+    stream
+        .write_all(b"GET http://localhost/ HTTP/1.1\r\nHost: loc")
+        .unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+    let response = read_raw_response(&mut stream);
+    assert!(response.contains(" 400 "), "{}", response);
+    assert!(response.contains("Incomplete request"), "{}", response);
+}
+
+#[test]
+fn test_large_request_head() {
+    use std::io::Write;
+
+    let mut proxy = Proxy::default();
+    proxy.register(
+        Mock::new("GET", "http://localhost/hello")
+            .match_header("x-header-40", Matcher::Exact("40".into()))
+            .create(),
+    );
+    proxy.start();
+
+    let mut head = String::from("GET http://localhost/hello HTTP/1.1\r\nHost: localhost\r\n");
+    for i in 0..50 {
+        head += &format!("X-Header-{}: {}\r\n", i, i);
+    }
+    head += &format!("Cookie: session={}\r\n\r\n", "a".repeat(8000));
+
+    let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+    stream.set_nodelay(true).unwrap();
+    for part in head.as_bytes().chunks(700) {
+        stream.write_all(part).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+
+    let response = read_raw_response(&mut stream);
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+}
+
+#[test]
+fn test_bad_request_targets() {
+    use std::io::Write;
+
+    let mut proxy = Proxy::default();
+    proxy.start();
+
+    for head in [
+        "GET http://a:99999/x HTTP/1.1\r\n\r\n",
+        "GET http://[::1/x HTTP/1.1\r\n\r\n",
+        "CONNECT [::1]:99999 HTTP/1.1\r\n\r\n",
+    ] {
+        let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+        stream.write_all(head.as_bytes()).unwrap();
+        let response = read_raw_response(&mut stream);
+        assert!(response.contains(" 400 "), "{}: {}", head, response);
+    }
+
+    let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+    stream
+        .write_all(b"CONNECT [::1]:443 HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let head = read_raw_head(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+}
+
+#[test]
+fn test_request_head_limits() {
+    use std::io::Write;
+
+    let mut proxy = Proxy::builder()
+        .with_max_head_size(1024)
+        .with_max_headers(4)
+        .build();
+    proxy.start();
+
+    let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+    write!(
+        stream,
+        "GET http://localhost/ HTTP/1.1\r\nCookie: {}\r\n\r\n",
+        "a".repeat(2000)
+    )
+    .unwrap();
+    let response = read_raw_response(&mut stream);
+    assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+
+    let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+    stream
+        .write_all(
+            b"GET http://localhost/ HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\nE: 5\r\n\r\n",
+        )
+        .unwrap();
+    let response = read_raw_response(&mut stream);
+    assert!(response.contains(" 431 "), "{}", response);
+}
+
+#[test]
+fn test_request_body_limit() {
+    use std::io::Write;
+
+    let mut proxy = Proxy::builder().with_max_body_size(16).build();
+    proxy.register(Mock::new("POST", "http://localhost/upload").create());
+    proxy.start();
+
+    let send = |request: &[u8]| {
+        let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+        stream.write_all(request).unwrap();
+        read_raw_response(&mut stream)
+    };
+    let response =
+        send(b"POST http://localhost/upload HTTP/1.1\r\nContent-Length: 1000000000000000\r\n\r\n");
+    assert!(response.contains(" 413 "), "{}", response);
+    let response = send(
+        b"POST http://localhost/upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+          ffffffffffffffff\r\n",
+    );
+    assert!(response.contains(" 413 "), "{}", response);
+    let response = send(
+        b"POST http://localhost/upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+          8\r\n12345678\r\n8\r\n12345678\r\n1\r\n9\r\n0\r\n\r\n",
+    );
+    assert!(response.contains(" 413 "), "{}", response);
+    let response = send(b"POST http://localhost/upload HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    // rejected requests are still recorded, with the status they were answered with
+    let statuses: Vec<u16> = proxy
+        .received_requests()
+        .iter()
+        .map(|entry| entry.status().as_u16())
+        .collect();
+    assert_eq!(statuses, [413, 413, 413, 200]);
+    assert_eq!(proxy.unmatched_requests().len(), 3);
+}
+
+#[tokio::test]
+async fn test_chunked_response_body() {
+    let mut proxy = Proxy::default();
+    proxy.register(
+        Mock::new("GET", "https://localhost/chunks")
+            .with_chunked_body(vec!["hello", " ", "world"])
+            .create(),
+    );
+    proxy.register(
+        Mock::new("GET", "https://localhost/reader")
+            .with_body_reader(std::io::Cursor::new(vec![7; 20_000]))
+            .create(),
+    );
+    proxy.start();
+
+    let client = build_client(&proxy);
+    let response = client.get("https://localhost/chunks").send().await.unwrap();
+    assert_eq!(response.headers()["transfer-encoding"], "chunked");
+    assert_eq!(response.text().await.unwrap(), "hello world");
+
+    for _ in 0..2 {
+        let response = client.get("https://localhost/reader").send().await.unwrap();
+        assert_eq!(response.bytes().await.unwrap(), vec![7; 20_000]);
+    }
+}
+
+#[test]
+fn test_chunk_delay_and_trailers() {
+    use std::io::{Read, Write};
+
+    let mut proxy = Proxy::default();
+    proxy.register(
+        Mock::new("GET", "http://localhost/slow")
+            .with_chunked_body(vec!["a", "b", "c"])
+            .with_chunk_delay(std::time::Duration::from_millis(100))
+            .with_trailer("x-checksum", "abc")
+            .create(),
+    );
+    proxy.start();
+
+    let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+    let start = std::time::Instant::now();
+    stream
+        .write_all(b"GET http://localhost/slow HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(start.elapsed() >= std::time::Duration::from_millis(200));
+    assert!(response.contains("Trailer: x-checksum\r\n"), "{}", response);
+    assert!(
+        response.ends_with("\r\n\r\n1\r\na\r\n1\r\nb\r\n1\r\nc\r\n0\r\nx-checksum: abc\r\n\r\n"),
+        "{}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_response_delay() {
+    use std::time::{Duration, Instant};
+
+    let mut proxy = Proxy::builder()
+        .with_default_delay(Duration::from_millis(300))
+        .build();
+    proxy.register(
+        Mock::new("GET", "http://localhost/fast")
+            .with_delay(Duration::ZERO)
+            .create(),
+    );
+    proxy.register(
+        Mock::new("GET", "http://localhost/jitter")
+            .with_delay_range(Duration::from_millis(100), Duration::from_millis(200))
+            .create(),
+    );
+    proxy.register(Mock::new("GET", "http://localhost/default").create());
+    proxy.start();
+
+    let client = build_client(&proxy);
+    let timed = |path: &'static str| {
+        let request = client.get(format!("http://localhost{}", path)).send();
+        async move {
+            let start = Instant::now();
+            request.await.unwrap();
+            start.elapsed()
+        }
+    };
+
+    assert!(timed("/fast").await < Duration::from_millis(300));
+    let jitter = timed("/jitter").await;
+    assert!(jitter >= Duration::from_millis(100), "{:?}", jitter);
+    assert!(jitter < Duration::from_millis(300), "{:?}", jitter);
+    assert!(timed("/default").await >= Duration::from_millis(300));
+}
+
+#[test]
+fn test_response_throttle() {
+    use std::io::{Read, Write};
+    use std::time::{Duration, Instant};
+
+    let mut proxy = Proxy::default();
+    proxy.register(
+        Mock::new("GET", "http://localhost/throttled")
+            .with_body_from_json("a".repeat(998))
+            .unwrap()
+            .with_throttle(2000)
+            .create(),
+    );
+    proxy.register(
+        Mock::new("GET", "http://localhost/spread")
+            .with_body_from_json("a".repeat(98))
+            .unwrap()
+            .with_body_duration(Duration::from_millis(300))
+            .create(),
+    );
+    proxy.start();
+
+    for path in ["throttled", "spread"] {
+        let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+        write!(
+            stream,
+            "GET http://localhost/{} HTTP/1.1\r\nConnection: close\r\n\r\n",
+            path
+        )
+        .unwrap();
+
+        let start = Instant::now();
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte).unwrap();
+        let first_byte = start.elapsed();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        let total = start.elapsed();
+
+        assert!(first_byte < Duration::from_millis(200), "{:?}", first_byte);
+        assert!(total >= Duration::from_millis(280), "{}: {:?}", path, total);
+        assert!(total < Duration::from_secs(2), "{}: {:?}", path, total);
+    }
+}
+
+#[test]
+fn test_faults() {
+    use std::io::{Read, Write};
+
+    let mut proxy = Proxy::default();
+    let faults = [
+        ("reset", Fault::ConnectionReset),
+        ("empty", Fault::EmptyResponse),
+        ("truncate", Fault::TruncateBodyAfter(5)),
+        ("garbage", Fault::GarbageBytes),
+        ("hang", Fault::HangForever),
+    ];
+    for (path, fault) in faults {
+        proxy.register(
+            Mock::new("GET", &format!("http://localhost/{}", path))
+                .with_body_from_json("0123456789")
+                .unwrap()
+                .with_fault(fault)
+                .create(),
+        );
+    }
+    proxy.start();
+
+    let send = |path: &str| {
+        let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+        write!(stream, "GET http://localhost/{} HTTP/1.1\r\n\r\n", path).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).map(|_| response)
+    };
+
+    let err = send("reset").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+
+    assert!(send("empty").unwrap().is_empty());
+
+    let truncated = String::from_utf8(send("truncate").unwrap()).unwrap();
+    assert!(
+        truncated.contains("Content-Length: 12\r\n"),
+        "{}",
+        truncated
+    );
+    assert!(truncated.ends_with("\r\n\r\n\"0123"), "{}", truncated);
+
+    let garbage = send("garbage").unwrap();
+    assert_eq!(garbage.len(), 64);
+
+    let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+    stream
+        .set_read_timeout(Some(std::time::Duration::from_millis(300)))
+        .unwrap();
+    stream
+        .write_all(b"GET http://localhost/hang HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let err = stream.read(&mut [0; 1]).unwrap_err();
+    assert!(matches!(
+        err.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    ));
+    proxy.stop();
+}
+
+#[tokio::test]
+async fn test_close_before_tls_handshake() {
+    let mut proxy = Proxy::default();
+    let handle = proxy.register(
+        Mock::new("GET", "https://refused.com/")
+            .with_fault(Fault::CloseBeforeTlsHandshake)
+            .create(),
+    );
+    proxy.register(Mock::new("GET", "https://accepted.com/").create());
+    // neither a mock without a host, nor one behind another for the same host, refuses
+    proxy.register(
+        Mock::new("GET", "/")
+            .with_fault(Fault::CloseBeforeTlsHandshake)
+            .create(),
+    );
+    proxy.register(
+        Mock::new("POST", "https://accepted.com/")
+            .with_fault(Fault::CloseBeforeTlsHandshake)
+            .create(),
+    );
+    proxy.start();
+
+    let client = build_client(&proxy);
+    assert!(client.get("https://refused.com/").send().await.is_err());
+    assert_eq!(handle.hits(), 1);
+
+    let response = client.get("https://accepted.com/").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+/// A plain HTTP server standing in for an upstream, sending back the head of every request
+fn stand_in_server() -> (std::net::SocketAddr, std::sync::mpsc::Receiver<String>) {
+    use std::io::Write;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let head = read_raw_head(&mut stream);
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nX-Upstream: yes\r\nContent-Length: 8\r\n\r\nupstream",
+                )
+                .unwrap();
+            if sender.send(head).is_err() {
+                return;
+            }
+        }
+    });
+    (address, receiver)
+}
+
+#[tokio::test]
+async fn test_passthrough_to_upstream() {
+    let (upstream, heads) = stand_in_server();
+    let mut proxy = Proxy::builder()
+        .passthrough(Passthrough::hosts(["api.test"]))
+        .with_upstream(upstream)
+        .build();
+    proxy.register(Mock::new("GET", "https://api.test/mocked").create());
+    proxy.start();
+
+    let client = build_client(&proxy);
+    let response = client.get("https://api.test/mocked").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("x-upstream").is_none());
+
+    let response = client
+        .get("https://api.test/other?page=2")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-upstream"], "yes");
+    assert_eq!(response.text().await.unwrap(), "upstream");
+    let head = heads.recv().unwrap();
+    assert!(
+        head.starts_with("GET /other?page=2 HTTP/1.1\r\n"),
+        "{}",
+        head
+    );
+    assert!(head.contains("Host: api.test\r\n"), "{}", head);
+
+    // the target and port are passed on as received, not as normalized for matching
+    client
+        .get("https://api.test:8443/other?flag&name=a%20b")
+        .send()
+        .await
+        .unwrap();
+    let head = heads.recv().unwrap();
+    assert!(
+        head.starts_with("GET /other?flag&name=a%20b HTTP/1.1\r\n"),
+        "{}",
+        head
+    );
+    assert!(head.contains("Host: api.test:8443\r\n"), "{}", head);
+
+    let response = client.get("https://elsewhere.test/").send().await.unwrap();
+    assert_eq!(response.status(), 500);
+
+    assert_eq!(proxy.unmatched_requests().len(), 1);
+    let forwarded = proxy.received_requests_matching(|entry| entry.is_passed_through());
+    assert_eq!(forwarded.len(), 2);
+}
+
+#[tokio::test]
+async fn test_passthrough_to_origin() {
+    let (origin, heads) = stand_in_server();
+    let mut proxy = Proxy::builder().passthrough(Passthrough::All).build();
+    proxy.start();
+
+    let client = build_client(&proxy);
+    let response = client
+        .get(format!("http://127.0.0.1:{}/direct", origin.port()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text().await.unwrap(), "upstream");
+    let head = heads.recv().unwrap();
+    assert!(head.starts_with("GET /direct HTTP/1.1\r\n"), "{}", head);
+    assert!(
+        head.contains(&format!("Host: 127.0.0.1:{}\r\n", origin.port())),
+        "{}",
+        head
+    );
+}
+
+#[tokio::test]
+async fn test_passthrough_upstream_failures() {
+    // accepts connections, but never answers on them
+    let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let silent_address = silent.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let _streams: Vec<_> = silent.incoming().collect();
+    });
+    let mut proxy = Proxy::builder()
+        .passthrough(Passthrough::All)
+        .with_upstream(silent_address)
+        .with_upstream_timeout(std::time::Duration::from_millis(200))
+        .build();
+    proxy.start();
+
+    let client = build_client(&proxy);
+    let response = client.get("https://api.test/").send().await.unwrap();
+    assert_eq!(response.status(), 504);
+    proxy.stop();
+
+    // nothing listens on the port once the listener is dropped
+    let closed = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut proxy = Proxy::builder()
+        .passthrough(Passthrough::All)
+        .with_upstream(closed)
+        .build();
+    proxy.start();
+
+    let client = build_client(&proxy);
+    let response = client.get("https://api.test/").send().await.unwrap();
+    assert_eq!(response.status(), 502);
+}
+
+/// A fresh directory under the system temp directory, removed with its contents on drop, so
+/// failing tests don't leave it behind
+struct TempDir(std::path::PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("mock_proxy_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl AsRef<std::path::Path> for TempDir {
+    fn as_ref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[tokio::test]
+async fn test_record_and_replay() {
+    let dir = TempDir::new("recording");
+
+    let (upstream, _heads) = stand_in_server();
+    let mut recording = Proxy::builder()
+        .passthrough(Passthrough::All)
+        .with_upstream(upstream)
+        .record_to(&dir)
+        .build();
+    recording.start();
+    let client = build_client(&recording);
+    for _ in 0..2 {
+        client.get("https://api.test/a").send().await.unwrap();
+    }
+    client
+        .post("https://api.test/b")
+        .body("payload")
+        .send()
+        .await
+        .unwrap();
+    recording.stop();
+
+    let fixtures = std::fs::read_dir(&dir)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension().unwrap() == "json")
+        .count();
+    assert_eq!(fixtures, 3);
+
+    let mut replay = Proxy::default();
+    let handles = replay.replay_from(&dir).unwrap();
+    assert_eq!(handles.len(), 2);
+    replay.start();
+
+    let client = build_client(&replay);
+    let response = client.get("https://api.test/a").send().await.unwrap();
+    assert_eq!(response.headers()["x-upstream"], "yes");
+    assert_eq!(response.text().await.unwrap(), "upstream");
+    let response = client
+        .post("https://api.test/b")
+        .body("payload")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let response = client
+        .post("https://api.test/b")
+        .body("other")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 500);
+
+    let bad = dir.join("zz_bad.json");
+    std::fs::write(
+        &bad,
+        r#"{ "request": { "method": "GET", "url": "https://[::1/" },
+             "response": { "status": 200, "headers": [] } }"#,
+    )
+    .unwrap();
+    match Proxy::default().replay_from(&dir) {
+        Err(Error::InvalidFile { path, message }) => {
+            assert_eq!(path, bad);
+            assert!(message.starts_with("Bad url:"), "{}", message);
+        }
+        other => panic!("expected an invalid file error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_har_import_and_export() {
+    let dir = TempDir::new("har");
+    let session = dir.join("session.har");
+    std::fs::write(
+        &session,
+        json::stringify(json::object! {
+            log: { entries: [
+                { request: { method: "GET", url: "https://api.test/poll" },
+                  response: { status: 202, headers: [{ name: "x-attempt", value: "1" }],
+                              content: { text: "first" } } },
+                { request: { method: "GET", url: "https://api.test/poll" },
+                  response: { status: 200, headers: [{ name: "Content-Length", value: "999" }],
+                              content: { text: "second" } } },
+                { request: { method: "GET", url: "https://api.test/logo.png" },
+                  response: { status: 200, headers: [],
+                              content: { text: "AAH/", encoding: "base64" } } },
+            ] },
+        }),
+    )
+    .unwrap();
+
+    let mut proxy = Proxy::default();
+    assert_eq!(proxy.load_har(&session).unwrap().len(), 3);
+    proxy.start();
+
+    let client = build_client(&proxy);
+    let response = client.get("https://api.test/poll").send().await.unwrap();
+    assert_eq!(response.status(), 202);
+    assert_eq!(response.headers()["x-attempt"], "1");
+    assert_eq!(response.text().await.unwrap(), "first");
+    for _ in 0..2 {
+        let response = client.get("https://api.test/poll").send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "second");
+    }
+    let response = client
+        .get("https://api.test/logo.png")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.bytes().await.unwrap().as_ref(), &[0, 1, 255]);
+
+    let exported = dir.join("exported.har");
+    proxy.export_har(&exported).unwrap();
+    let har = json::parse(&std::fs::read_to_string(&exported).unwrap()).unwrap();
+    let entries = &har["log"]["entries"];
+    assert_eq!(entries.len(), 4);
+    assert_eq!(entries[0]["request"]["url"], "https://api.test/poll");
+    assert_eq!(entries[0]["response"]["status"], 202);
+    assert_eq!(entries[3]["response"]["content"]["encoding"], "base64");
+
+    let mut replay = Proxy::default();
+    assert_eq!(replay.load_har(&exported).unwrap().len(), 4);
+
+    let bad = dir.join("bad.har");
+    std::fs::write(
+        &bad,
+        json::stringify(json::object! {
+            log: { entries: [
+                { request: { method: "GET", url: "https://api.test/" },
+                  response: { status: 200, headers: [], content: {} } },
+                { request: { method: "GET", url: "https://[::1/" },
+                  response: { status: 200, headers: [], content: {} } },
+            ] },
+        }),
+    )
+    .unwrap();
+    match replay.load_har(&bad) {
+        Err(Error::InvalidFile { message, .. }) => {
+            assert!(message.starts_with("entry 1: Bad url:"), "{}", message);
+        }
+        other => panic!("expected an invalid file error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_header_matching() {
+    let mut proxy = Proxy::default();
+    proxy.register(
+        Mock::new("GET", "https://localhost/hello")
+            .match_header("Authorization", Matcher::Exact("Bearer token".into()))
+            .match_header("accept", Matcher::Regex("^application/(.+)json$".into()))
+            .match_header("X-Debug", Matcher::Absent)
+            .with_status(201)
+            .create(),
+    );
+    proxy.register(
+        Mock::new("GET", "https://localhost/hello")
+            .match_header("authorization", Matcher::Present)
+            .with_status(403)
+            .create(),
+    );
+    proxy.start();
+
+    let client = build_client(&proxy);
+    let request = || {
+        client
+            .get("https://localhost/hello")
+            .header("authorization", "Bearer token")
+            .header("Accept", "application/vnd.api+json")
+    };
+
+    let response = request().send().await.unwrap();
+    assert_eq!(response.status(), 201);
+
+    let response = request().header("x-debug", "1").send().await.unwrap();
+    assert_eq!(response.status(), 403);
+
+    let response = client.get("https://localhost/hello").send().await.unwrap();
+    assert_eq!(response.status(), 500);
+}
+
+#[tokio::test]
+async fn test_body_matching() {
+    let mut proxy = Proxy::default();
+    proxy.register(
+        Mock::new("POST", "https://localhost/users")
+            .match_body(Matcher::PartialJson(json::object! { name: "Ada" }))
+            .with_status(201)
+            .create(),
+    );
+    proxy.register(
+        Mock::new("PUT", "http://localhost/upload")
+            .match_body(Matcher::Contains("middle".into()))
+            .match_body(Matcher::Regex("^start".into()))
+            .with_status(204)
+            .create(),
+    );
+    proxy.start();
+
+    let client = build_client(&proxy);
+
+    let response = client
+        .post("https://localhost/users")
+        .body(r#"{"id": 1, "name": "Ada", "tags": []}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 201);
+
+    let response = client
+        .post("https://localhost/users")
+        .body(r#"{"name": "Grace"}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 500);
+
+    let response = client
+        .put("http://localhost/upload")
+        .body("x".repeat(4096) + "start middle end")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 500);
+
+    let response = client
+        .put("http://localhost/upload")
+        .body("start ".to_owned() + &"x".repeat(4096) + " middle end")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+}
+
+#[test]
+fn test_chunked_request_body() {
+    use std::io::{Read, Write};
+
+    let mut proxy = Proxy::default();
+    proxy.register(
+        Mock::new("POST", "http://localhost/upload")
+            .match_body(Matcher::Exact("hello world".into()))
+            .create(),
+    );
+    proxy.start();
+
+    let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+    stream
+        .write_all(
+            b"POST http://localhost/upload HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+              Transfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n",
+        )
+        .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+}
+
+/// Reads the status line and headers of a single response from a raw connection
+fn read_raw_head(stream: &mut std::net::TcpStream) -> String {
+    use std::io::Read;
+
+    let mut response = Vec::new();
+    let mut byte = [0; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        response.push(byte[0]);
+    }
+    String::from_utf8(response).unwrap()
+}
+
+/// Reads a single response from a raw connection, relying on its `Content-Length`
+fn read_raw_response(stream: &mut std::net::TcpStream) -> String {
+    use std::io::Read;
+
+    let head = read_raw_head(stream);
+    let length = head
+        .lines()
+        .filter_map(|line| line.split_once(": "))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .map_or(0, |(_, length)| length.parse().unwrap());
+
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).unwrap();
+    head + &String::from_utf8(body).unwrap()
+}
+
+#[test]
+fn test_response_framing() {
+    use std::io::Write;
+
+    let mut proxy = Proxy::default();
+    proxy.register(
+        Mock::new("GET", "http://localhost/custom")
+            .with_header("content-length", "4")
+            .with_header("date", "Tue, 15 Nov 1994 08:12:31 GMT")
+            .with_body_from_json("hi")
+            .unwrap()
+            .create(),
+    );
+    proxy.register(
+        Mock::new("GET", "http://localhost/empty")
+            .with_status(204)
+            .create(),
+    );
+    proxy.register(
+        Mock::new("HEAD", "http://localhost/head")
+            .with_body_from_json("hidden")
+            .unwrap()
+            .create(),
+    );
+    proxy.start();
+
+    let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+    stream
+        .write_all(b"GET http://localhost/custom HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let response = read_raw_response(&mut stream).to_lowercase();
+    assert_eq!(
+        response.matches("content-length").count(),
+        1,
+        "{}",
+        response
+    );
+    assert_eq!(response.matches("date: ").count(), 1, "{}", response);
+    assert!(response.ends_with("\r\n\r\n\"hi\""), "{}", response);
+
+    stream
+        .write_all(b"GET http://localhost/empty HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let response = read_raw_response(&mut stream);
+    assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+    assert!(!response.contains("Content-Length"), "{}", response);
+    assert!(response.contains("Date: "), "{}", response);
+
+    stream
+        .write_all(b"HEAD http://localhost/head HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let head = read_raw_head(&mut stream);
+    assert!(head.contains("Content-Length: 8\r\n"), "{}", head);
+
+    stream
+        .write_all(b"GET http://localhost/empty HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+        .unwrap();
+    let response = read_raw_response(&mut stream);
+    assert!(response.starts_with("HTTP/1.0 204"), "{}", response);
+    assert!(
+        response.contains("Connection: keep-alive\r\n"),
+        "{}",
+        response
+    );
+}
+
+#[test]
+fn test_keep_alive() {
+    use std::io::{Read, Write};
+
+    let mut proxy = Proxy::default();
+    proxy.register(
+        Mock::new("GET", "http://localhost/hello")
+            .with_body_from_json("world")
+            .unwrap()
+            .create(),
+    );
+    proxy.start();
+
+    let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+    for _ in 0..3 {
+        stream
+            .write_all(b"GET http://localhost/hello HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let response = read_raw_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("\"world\""), "{}", response);
+    }
+
+    stream
+        .write_all(b"GET http://localhost/hello HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let response = read_raw_response(&mut stream);
+    assert!(response.contains("Connection: close\r\n"), "{}", response);
+    assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+
+    let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+    stream
+        .write_all(b"GET http://localhost/hello HTTP/1.0\r\n\r\n")
+        .unwrap();
+    read_raw_response(&mut stream);
+    assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+}
+
+#[test]
+fn test_stop_with_stalled_client() {
+    use std::io::Write;
+    use std::time::{Duration, Instant};
+
+    let mut proxy = Proxy::builder()
+        .with_idle_timeout(Duration::from_millis(200))
+        .build();
+    proxy.register(
+        Mock::new("GET", "http://localhost/large")
+            .with_chunked_body([vec![0; 64 << 20]])
+            .create(),
+    );
+    proxy.start();
+
+    // asks for a response far larger than the socket buffers, then never reads it
+    let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+    stream
+        .write_all(b"GET http://localhost/large HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+
+    let start = Instant::now();
+    proxy.stop();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    drop(stream);
+}
+
+#[test]
+fn test_pipelined_requests() {
+    use std::io::Write;
+
+    let mut proxy = Proxy::default();
+    proxy.register(Mock::new("GET", "http://localhost/first").create());
+    proxy.register(
+        Mock::new("POST", "http://localhost/second")
+            .match_body(Matcher::Exact("payload".into()))
+            .with_status(201)
+            .create(),
+    );
+    proxy.register(
+        Mock::new("GET", "http://localhost/third")
+            .with_status(202)
+            .create(),
+    );
+    proxy.start();
+
+    let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+    stream
+        .write_all(
+            b"GET http://localhost/first HTTP/1.1\r\nHost: localhost\r\n\r\n\
+              POST http://localhost/second HTTP/1.1\r\nContent-Length: 7\r\n\r\npayload\
+              GET http://localhost/third HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .unwrap();
+    for status in ["200", "201", "202"] {
+        let response = read_raw_response(&mut stream);
+        assert!(
+            response.starts_with(&format!("HTTP/1.1 {}", status)),
+            "{}",
+            response
+        );
+    }
+    assert_eq!(proxy.received_requests().len(), 3);
+}
+
+#[test]
+fn test_more_connections_than_workers() {
+    use std::io::Write;
+    use std::time::{Duration, Instant};
+
+    let mut proxy = Proxy::builder().with_worker_threads(2).build();
+    proxy.register(Mock::new("GET", "http://localhost/hello").create());
+    proxy.start();
+
+    // each connection is kept alive, sitting idle once answered
+    let start = Instant::now();
+    let mut streams = Vec::new();
+    for _ in 0..4 {
+        let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+        stream
+            .write_all(b"GET http://localhost/hello HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let response = read_raw_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        streams.push(stream);
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    for stream in &mut streams {
+        stream
+            .write_all(b"GET http://localhost/hello HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        assert!(read_raw_response(stream).starts_with("HTTP/1.1 200"));
+    }
+}
+
+#[test]
+fn test_idle_timeout() {
+    use std::io::{Read, Write};
+
+    let mut proxy = Proxy::builder()
+        .with_idle_timeout(std::time::Duration::from_millis(100))
+        .build();
+    proxy.register(Mock::new("GET", "http://localhost/hello").create());
+    proxy.start();
+
+    let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+    stream
+        .write_all(b"GET http://localhost/hello HTTP/1.1\r\n\r\n")
+        .unwrap();
+    read_raw_response(&mut stream);
+
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_stalled_client_does_not_block_others() {
+    use std::io::Write;
+
+    let mut proxy = Proxy::default();
+    proxy.register(Mock::new("GET", "https://localhost/hello").create());
+    proxy.start();
+
+    // opens a tunnel but never starts the TLS handshake
+    let mut stalled = std::net::TcpStream::connect(proxy.address()).unwrap();
+    stalled
+        .write_all(b"CONNECT localhost:443 HTTP/1.1\r\n\r\n")
+        .unwrap();
+
+    let client = build_client(&proxy);
+    let requests: Vec<_> = (0..4)
+        .map(|_| tokio::spawn(client.get("https://localhost/hello").send()))
+        .collect();
+
+    for request in requests {
+        let response = tokio::time::timeout(std::time::Duration::from_secs(3), request)
+            .await
+            .expect("requests should not wait for the stalled client")
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+}
+
+#[tokio::test]
+async fn test_certificate_cache() {
+    let mut proxy = Proxy::builder().try_build().unwrap();
+    proxy
+        .try_with_shared_leaf_key()
+        .unwrap()
+        .prewarm_certificates(["localhost", "hello.com"])
+        .unwrap();
+    proxy.register(Mock::new("GET", "/hello").create());
+    proxy.start();
+    assert_eq!(proxy.certificates.len(), 2);
+
+    for url in [
+        "https://localhost/hello",
+        "https://hello.com/hello",
+        "https://other.com/hello",
+    ] {
+        // a fresh client per request forces a new tunnel each time
+        let response = build_client(&proxy).get(url).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+    }
+    assert_eq!(proxy.certificates.len(), 3);
+}
+
+#[tokio::test]
+async fn test_expectations() {
+    let mut proxy = Proxy::default();
+    let hello = proxy.register(
+        Mock::new("GET", "http://localhost/hello")
+            .expect(2)
+            .create(),
+    );
+    let other = proxy.register(
+        Mock::new("GET", "http://localhost/other")
+            .expect_at_least(1)
+            .expect_at_most(3)
+            .create(),
+    );
+    proxy.start();
+
+    let client = build_client(&proxy);
+    for _ in 0..2 {
+        client.get("http://localhost/hello").send().await.unwrap();
+    }
+    client.get("http://localhost/other").send().await.unwrap();
+
+    assert_eq!(hello.hits(), 2);
+    assert_eq!(other.hits(), 1);
+    hello.assert();
+    other.assert();
+    proxy.verify();
+}
+
+#[tokio::test]
+#[should_panic(expected = "2 mock expectation(s) were not met:\n  \
+    GET localhost/never was expected to be hit at least 1 time(s), but was hit 0 time(s)\n  \
+    GET localhost/once was expected to be hit exactly 1 time(s), but was hit 2 time(s)")]
+async fn test_verify_reports_unmet_expectations() {
+    let mut proxy = Proxy::default();
+    proxy.register(
+        Mock::new("GET", "http://localhost/never")
+            .expect_at_least(1)
+            .create(),
+    );
+    proxy.register(Mock::new("GET", "http://localhost/once").expect(1).create());
+    proxy.register(Mock::new("GET", "http://localhost/unchecked").create());
+    proxy.start();
+
+    let client = build_client(&proxy);
+    for _ in 0..2 {
+        client.get("http://localhost/once").send().await.unwrap();
+    }
+
+    proxy.verify();
+}
+
+#[tokio::test]
+async fn test_received_requests() {
+    let mut proxy = Proxy::default();
+    let users = proxy.register(Mock::new("POST", "https://localhost/users").create());
+    proxy.start();
+
+    let client = build_client(&proxy);
+    client
+        .post("https://localhost/users?page=2&sort=name%20asc")
+        .header("x-request-id", "abc")
+        .body("hello")
+        .send()
+        .await
+        .unwrap();
+    client.post("https://localhost/users").send().await.unwrap();
+    client.get("http://localhost/missing").send().await.unwrap();
+
+    let received = proxy.received_requests();
+    assert_eq!(received.len(), 3);
+
+    let first = &received[0];
+    assert_eq!(first.scheme(), Scheme::Https);
+    assert_eq!(first.status(), 500);
+    assert_eq!(first.mock_id(), None);
+    assert_eq!(first.request().method(), "POST");
+    assert_eq!(first.request().host(), Some("localhost"));
+    assert_eq!(first.request().path(), "/users");
+    assert_eq!(
+        first.request().query_pairs(),
+        vec![
+            ("page".to_string(), "2".to_string()),
+            ("sort".to_string(), "name asc".to_string())
+        ]
+    );
+    assert_eq!(first.request().header("X-Request-Id"), Some("abc"));
+    assert_eq!(first.request().body(), b"hello");
+
+    let matched = proxy.received_requests_for(&users);
+    assert_eq!(matched.len(), 1);
+    assert_eq!(matched[0].status(), 200);
+    assert!(matched[0].timestamp() >= first.timestamp());
+
+    let unmatched = proxy.unmatched_requests();
+    assert_eq!(unmatched.len(), 2);
+    assert_eq!(unmatched[1].scheme(), Scheme::Http);
+    assert_eq!(unmatched[1].request().path(), "/missing");
+}
+
+#[tokio::test]
+async fn test_near_miss_diagnostics() {
+    let mut proxy = Proxy::default();
+    proxy.register(Mock::new("GET", "https://localhost/elsewhere").create());
+    proxy.register(
+        Mock::new("POST", "https://localhost/users")
+            .match_header("content-type", Matcher::Exact("application/json".into()))
+            .match_body(Matcher::PartialJson(json::object! { name: "Ada" }))
+            .create(),
+    );
+    proxy.start();
+
+    let client = build_client(&proxy);
+    let response = client
+        .post("https://localhost/users")
+        .body(r#"{"name": "Grace"}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 500);
+
+    let text = response.text().await.unwrap();
+    assert_eq!(
+        text,
+        "No matching response\n\
+         \n\
+         Closest mocks:\n  \
+         POST localhost/users (2 difference(s))\n    \
+         - header `content-type`: expected \"application/json\", got absent\n    \
+         - body: expected JSON containing {\"name\":\"Ada\"}, got \"{\\\"name\\\": \\\"Grace\\\"}\"\n  \
+         GET localhost/elsewhere (2 difference(s))\n    \
+         - method: expected GET, got POST\n    \
+         - path: expected /elsewhere, got /users"
+    );
+}
+
+#[tokio::test]
+#[should_panic(expected = "1 request(s) did not match any mock:\n  \
+    GET localhost/missing: No matching response\n    \n    Closest mocks:\n      \
+    GET localhost/hello (1 difference(s))\n        \
+    - path: expected /hello, got /missing")]
+async fn test_verify_reports_unmatched_requests() {
+    let mut proxy = Proxy::default();
+    proxy.fail_verify_on_unmatched();
+    proxy.register(Mock::new("GET", "http://localhost/hello").create());
+    proxy.start();
+
+    let client = build_client(&proxy);
+    client.get("http://localhost/hello").send().await.unwrap();
+    client.get("http://localhost/missing").send().await.unwrap();
+
+    proxy.verify();
+}
+
+#[tokio::test]
+async fn test_responder() {
+    let mut proxy = Proxy::default();
+    proxy.register(
+        Mock::new("POST", "https://localhost/echo?shout=yes")
+            .with_status(404)
+            .with_responder(|request| {
+                let shout = request
+                    .query_pairs()
+                    .iter()
+                    .any(|(key, value)| key == "shout" && value == "yes");
+                let mut body = String::from_utf8_lossy(request.body()).into_owned();
+                if shout {
+                    body = body.to_uppercase();
+                }
+
+                Response::new()
+                    .with_status(201)
+                    .with_header("x-method", request.method())
+                    .with_header("x-agent", request.header("user-agent").unwrap_or("none"))
+                    .with_body(body)
+                    .create()
+            })
+            .create(),
+    );
+    proxy.start();
+
+    let client = build_client(&proxy);
+    let response = client
+        .post("https://localhost/echo?shout=yes")
+        .header("user-agent", "tests")
+        .body("hello")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 201);
+    assert_eq!(response.headers()["x-method"], "POST");
+    assert_eq!(response.headers()["x-agent"], "tests");
+    assert_eq!(response.text().await.unwrap(), "HELLO");
+}
+
+#[tokio::test]
+async fn test_response_sequence() {
+    let mut proxy = Proxy::default();
+    let sequence = || {
+        vec![
+            Response::new().with_status(503).create(),
+            Response::new().with_status(201).create(),
+        ]
+    };
+    for (path, exhausted) in [
+        ("/repeat", Exhausted::RepeatLast),
+        ("/cycle", Exhausted::Cycle),
+        ("/fall", Exhausted::FallThrough),
+        ("/error", Exhausted::Error),
+    ] {
+        proxy.register(
+            Mock::new("GET", path)
+                .with_response_sequence(sequence())
+                .when_sequence_exhausted(exhausted)
+                .create(),
+        );
+    }
+    let fallback = proxy.register(Mock::new("GET", "/fall").with_status(404).create());
+    proxy.start();
+
+    let client = build_client(&proxy);
+    let statuses = |path: &'static str| {
+        let client = client.clone();
+        async move {
+            let mut statuses = Vec::new();
+            for _ in 0..4 {
+                let url = format!("https://localhost{}", path);
+                statuses.push(client.get(url).send().await.unwrap().status().as_u16());
+            }
+            statuses
+        }
+    };
+
+    assert_eq!(statuses("/repeat").await, [503, 201, 201, 201]);
+    assert_eq!(statuses("/cycle").await, [503, 201, 503, 201]);
+    assert_eq!(statuses("/fall").await, [503, 201, 404, 404]);
+    assert_eq!(statuses("/error").await, [503, 201, 500, 500]);
+    assert_eq!(fallback.hits(), 2);
+}
+
+#[tokio::test]
+async fn test_change_mocks_while_running() {
+    let mut proxy = Proxy::default();
+    proxy.start();
+    let client = build_client(&proxy);
+    let status = |url: &'static str| {
+        let client = client.clone();
+        async move { client.get(url).send().await.unwrap().status().as_u16() }
+    };
+
+    assert_eq!(status("http://localhost/hello").await, 500);
+
+    let hello = proxy.register(Mock::new("GET", "/hello").with_status(201).create());
+    proxy.register(Mock::new("GET", "/hello").with_status(202).create());
+    assert_eq!(status("http://localhost/hello").await, 201);
+
+    let hello = proxy.replace(&hello, Mock::new("GET", "/hello").with_status(203).create());
+    assert_eq!(status("http://localhost/hello").await, 203);
+
+    assert!(proxy.remove(&hello));
+    assert!(!proxy.remove(&hello));
+    assert_eq!(status("http://localhost/hello").await, 202);
+
+    proxy.reset();
+    assert!(proxy.received_requests().is_empty());
+    assert_eq!(status("http://localhost/hello").await, 500);
+    assert_eq!(proxy.received_requests().len(), 1);
+}
+
+#[tokio::test]
+async fn test_mocks_from_files() {
+    let dir = TempDir::new("defs");
+    std::fs::write(
+        dir.join("a-users.yaml"),
+        r#"
+mocks:
+  - method: post
+    url: https://api.test/users
+    headers:
+      Authorization: Bearer token
+    body: { partial_json: { name: alice } }
+    response:
+      status: 201
+      headers: { Content-Type: application/json }
+      body: { id: 1 }
+    expect: 1
+  - method: GET
+    url: https://api.test/users?page=1
+    query:
+      size: { regex: "^[0-9]+$" }
+    response: { body_file: users.txt }
+"#,
+    )
+    .unwrap();
+    std::fs::write(dir.join("users.txt"), "alice, bob").unwrap();
+    std::fs::write(
+        dir.join("b-health.json"),
+        r#"[{ "method": "GET", "url": "/health", "response": { "body": "ok" } }]"#,
+    )
+    .unwrap();
+
+    let mut proxy = Proxy::default();
+    let handles = proxy.load_mocks_from_dir(&dir).unwrap();
+    assert_eq!(handles.len(), 3);
+    proxy.start();
+
+    let client = build_client(&proxy);
+    let response = client
+        .post("https://api.test/users")
+        .header("authorization", "Bearer token")
+        .body(r#"{"name": "alice", "age": 30}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 201);
+    assert_eq!(
+        json::parse(&response.text().await.unwrap()).unwrap(),
+        json::object! { id: 1 }
+    );
+
+    let response = client
+        .get("https://api.test/users?size=10&page=1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text().await.unwrap(), "alice, bob");
+    let response = client
+        .get("https://api.test/users?size=ten&page=1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 500);
+
+    let response = client.get("http://localhost/health").send().await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "ok");
+    handles[0].assert();
+}
+
+#[test]
+fn test_invalid_mock_file() {
+    let dir = TempDir::new("bad_defs");
+    let path = dir.join("mocks.json");
+    std::fs::write(
+        &path,
+        r#"{ "mocks": [
+            { "method": "GET", "url": "/ok" },
+            { "method": "GET", "url": "/bad", "headers": { "Accept": { "regex": "(" } } }
+        ] }"#,
+    )
+    .unwrap();
+
+    let mut proxy = Proxy::default();
+    match proxy.load_mocks_from_file(&path) {
+        Err(Error::InvalidFile {
+            path: file,
+            message,
+        }) => {
+            assert_eq!(file, path);
+            assert!(
+                message.starts_with("mock 1: header `Accept`:"),
+                "{}",
+                message
+            );
+        }
+        other => panic!("expected an invalid file error, got {:?}", other),
+    }
+    assert!(proxy
+        .load_mocks_from_file(dir.join("missing.yaml"))
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_wiremock_mappings() {
+    let root = TempDir::new("wiremock");
+    std::fs::create_dir_all(root.join("mappings")).unwrap();
+    std::fs::create_dir_all(root.join("__files")).unwrap();
+    std::fs::write(root.join("__files/user.json"), r#"{"id": 7}"#).unwrap();
+    std::fs::write(
+        root.join("mappings/users.json"),
+        json::stringify(json::object! {
+            mappings: [
+                {
+                    request: {
+                        method: "GET",
+                        urlPathPattern: "/users/[0-9]+",
+                        queryParameters: { expand: { equalTo: "true" } },
+                        headers: { Accept: { contains: "json" } },
+                    },
+                    response: { status: 200, bodyFileName: "user.json",
+                                headers: { "Content-Type": "application/json" } },
+                },
+                {
+                    priority: 1,
+                    request: {
+                        method: "POST",
+                        urlPath: "/users",
+                        bodyPatterns: [{ equalToJson: "{\"name\": \"alice\"}",
+                                         ignoreExtraElements: true }],
+                    },
+                    response: { status: 201, jsonBody: { created: true },
+                                fixedDelayMilliseconds: 10 },
+                },
+            ],
+        }),
+    )
+    .unwrap();
+
+    let mut proxy = Proxy::default();
+    let handles = proxy.load_wiremock(&root).unwrap();
+    assert_eq!(handles.len(), 2);
+    proxy.start();
+
+    let client = build_client(&proxy);
+    let response = client
+        .get("http://localhost/users/42?expand=true")
+        .header("accept", "application/json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text().await.unwrap(), r#"{"id": 7}"#);
+    let response = client
+        .get("http://localhost/users/me?expand=true")
+        .header("accept", "application/json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 500);
+    let response = client
+        .post("http://localhost/users")
+        .body(r#"{"name": "alice", "admin": false}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 201);
+
+    std::fs::write(
+        root.join("mappings/scenario.json"),
+        r#"{ "scenarioName": "login", "request": { "url": "/" }, "response": {} }"#,
+    )
+    .unwrap();
+    let error = Proxy::default().load_wiremock(&root).unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .ends_with("unsupported `scenarioName` in the mapping"),
+        "{}",
+        error
+    );
+
+    // WireMock matches any method when none is given, which isn't supported
+    std::fs::write(
+        root.join("mappings/scenario.json"),
+        r#"{ "request": { "url": "/" }, "response": {} }"#,
+    )
+    .unwrap();
+    let error = Proxy::default().load_wiremock(&root).unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .ends_with("missing `method`, matching any method is unsupported"),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn test_mocks_from_openapi() {
+    let dir = TempDir::new("openapi");
+    let spec = dir.join("users.json");
+    std::fs::write(
+        &spec,
+        json::stringify(json::object! {
+            openapi: "3.0.3",
+            servers: [{ url: "https://api.test/v1" }],
+            paths: {
+                "/users/{id}": {
+                    get: { responses: {
+                        "404": { description: "missing" },
+                        "200": { description: "found", content: { "application/json": {
+                            schema: { "$ref": "#/components/schemas/User" },
+                        } } },
+                    } },
+                },
+                "/users/me": {
+                    get: { responses: { "200": { description: "me", content: {
+                        "application/json": { example: { id: 1, name: "me" } },
+                    } } } },
+                },
+                "/health": {
+                    head: { responses: { "204": { description: "healthy" } } },
+                },
+            },
+            components: { schemas: { User: {
+                type: "object",
+                properties: {
+                    id: { type: "integer", minimum: 1 },
+                    name: { type: "string" },
+                    role: { type: "string", enum: ["admin", "user"] },
+                    tags: { type: "array", items: { type: "string" } },
+                },
+            } } },
+        }),
+    )
+    .unwrap();
+
+    let mut proxy = Proxy::from_openapi(&spec).unwrap();
+    proxy.start();
+
+    let client = build_client(&proxy);
+    let body = |response: reqwest::Response| async {
+        json::parse(&response.text().await.unwrap()).unwrap()
+    };
+    let response = client
+        .get("https://api.test/v1/users/42")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        body(response).await,
+        json::object! { id: 1, name: "string", role: "admin", tags: ["string"] }
+    );
+    let response = client
+        .get("https://api.test/v1/users/me")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(body(response).await, json::object! { id: 1, name: "me" });
+    let response = client
+        .head("https://api.test/v1/health")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+    let response = client
+        .get("https://api.test/v1/users/42/posts")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 500);
+}
+
+#[test]
+fn test_panicking_handler_closes_connection() {
+    use std::io::{Read, Write};
+
+    let mut proxy = Proxy::default();
+    proxy.register(
+        Mock::new("GET", "http://localhost/panic")
+            .with_responder(|_| panic!("responder failed"))
+            .create(),
+    );
+    proxy.start();
+
+    let mut stream = std::net::TcpStream::connect(proxy.address()).unwrap();
+    stream
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(b"GET http://localhost/panic HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .expect("connection should be closed rather than left open");
+    assert!(response.is_empty());
+}
@@ -0,0 +1,280 @@
+use crate::{Error, Fault, Matcher, Mock, Response};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use json::JsonValue;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Priority WireMock gives mappings that don't set one
+const DEFAULT_PRIORITY: i64 = 5;
+
+/// Mapping keys understood, or safely ignored as they don't change how requests are answered
+const MAPPING_KEYS: [&str; 8] = [
+    "id",
+    "uuid",
+    "name",
+    "persistent",
+    "metadata",
+    "priority",
+    "request",
+    "response",
+];
+const REQUEST_KEYS: [&str; 8] = [
+    "method",
+    "url",
+    "urlPath",
+    "urlPathPattern",
+    "queryParameters",
+    "headers",
+    "bodyPatterns",
+    "host",
+];
+const RESPONSE_KEYS: [&str; 10] = [
+    "status",
+    "statusMessage",
+    "headers",
+    "body",
+    "jsonBody",
+    "base64Body",
+    "bodyFileName",
+    "fixedDelayMilliseconds",
+    "delayDistribution",
+    "fault",
+];
+
+/// A mapping read from a file, kept until every file is read so they can be ordered by
+/// priority
+struct Mapping {
+    priority: i64,
+    mock: Mock,
+}
+
+/// Builds a [`Mock`] for every mapping in `root/mappings`, reading body files from
+/// `root/__files`
+///
+/// Mappings are ordered by priority, then by file name, as the first matching mock answers
+pub(crate) fn load(root: &Path) -> Result<Vec<Mock>, Error> {
+    let dir = root.join("mappings");
+    let io_error = |source| Error::Io {
+        path: dir.clone(),
+        source,
+    };
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(&dir).map_err(io_error)? {
+        let path = entry.map_err(io_error)?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let files = root.join("__files");
+    let mut mappings = Vec::new();
+    for path in paths {
+        let invalid = |message: String| Error::InvalidFile {
+            path: path.clone(),
+            message,
+        };
+        let text = fs::read_to_string(&path).map_err(|source| Error::Io {
+            path: path.clone(),
+            source,
+        })?;
+        let document = json::parse(&text).map_err(|err| invalid(err.to_string()))?;
+        if document["mappings"].is_array() {
+            for (index, mapping) in document["mappings"].members().enumerate() {
+                mappings.push(
+                    mapping_of(mapping, &files)
+                        .map_err(|message| invalid(format!("mapping {}: {}", index, message)))?,
+                );
+            }
+        } else {
+            mappings.push(mapping_of(&document, &files).map_err(invalid)?);
+        }
+    }
+
+    // stable, so mappings of equal priority keep their file order
+    mappings.sort_by_key(|mapping| mapping.priority);
+    Ok(mappings.into_iter().map(|mapping| mapping.mock).collect())
+}
+
+fn mapping_of(mapping: &JsonValue, files: &Path) -> Result<Mapping, String> {
+    supported(mapping, &MAPPING_KEYS, "the mapping")?;
+    let request = &mapping["request"];
+    supported(request, &REQUEST_KEYS, "`request`")?;
+    let priority = &mapping["priority"];
+    let priority = if priority.is_null() {
+        DEFAULT_PRIORITY
+    } else {
+        priority.as_i64().ok_or("`priority` should be a number")?
+    };
+
+    // WireMock takes a missing method to mean any, which can't be matched
+    let method = request["method"]
+        .as_str()
+        .ok_or("missing `method`, matching any method is unsupported")?;
+    if method == "ANY" {
+        return Err("unsupported method `ANY`".to_string());
+    }
+    let mut mock = if let Some(url) = request["url"].as_str() {
+        Mock::try_new(method, url).map_err(|err| err.to_string())?
+    } else {
+        let mut mock = Mock::new(method, "/");
+        if let Some(path) = request["urlPath"].as_str() {
+            mock.match_path(Matcher::Exact(path.to_string()));
+        } else if let Some(pattern) = request["urlPathPattern"].as_str() {
+            let matcher = Matcher::Regex(format!("^(?:{})$", pattern));
+            matcher.validate().map_err(|err| err.to_string())?;
+            mock.match_path(matcher);
+        } else {
+            mock.match_path(Matcher::Present);
+        }
+        mock
+    };
+    if !request["host"].is_null() {
+        match matcher_of(&request["host"]) {
+            Ok(Matcher::Exact(host)) => mock.host = Some(host),
+            _ => return Err("unsupported `host` matcher, only equalTo is".to_string()),
+        }
+    }
+
+    for (name, pattern) in request["queryParameters"].entries() {
+        let matcher = matcher_of(pattern).map_err(|err| format!("query `{}`: {}", name, err))?;
+        mock.match_query(name, matcher);
+    }
+    for (name, pattern) in request["headers"].entries() {
+        let matcher = matcher_of(pattern).map_err(|err| format!("header `{}`: {}", name, err))?;
+        mock.match_header(name, matcher);
+    }
+    for (index, pattern) in request["bodyPatterns"].members().enumerate() {
+        let matcher =
+            matcher_of(pattern).map_err(|err| format!("body pattern {}: {}", index, err))?;
+        mock.match_body(matcher);
+    }
+
+    let response = &mapping["response"];
+    if !response.is_null() {
+        supported(response, &RESPONSE_KEYS, "`response`")?;
+        mock.response = response_of(response, files)?;
+    }
+    Ok(Mapping { priority, mock })
+}
+
+fn response_of(response: &JsonValue, files: &Path) -> Result<Response, String> {
+    let mut built = Response::new();
+    let status = &response["status"];
+    if !status.is_null() {
+        let status = status.as_u16().ok_or("`status` should be a number")?;
+        built
+            .try_with_status(status)
+            .map_err(|err| err.to_string())?;
+    }
+    for (name, value) in response["headers"].entries() {
+        if let Some(value) = value.as_str() {
+            built.with_header(name, value);
+        } else if value.is_array() {
+            for value in value.members() {
+                built.with_header(name, value.as_str().unwrap_or_default());
+            }
+        } else {
+            return Err(format!("header `{}` should be a string or a list", name));
+        }
+    }
+
+    if let Some(body) = response["body"].as_str() {
+        built.with_body(body);
+    } else if !response["jsonBody"].is_null() {
+        built.with_body_from_json(response["jsonBody"].clone());
+    } else if let Some(body) = response["base64Body"].as_str() {
+        built.with_body(BASE64.decode(body).map_err(|err| err.to_string())?);
+    } else if let Some(file) = response["bodyFileName"].as_str() {
+        let path = files.join(file);
+        let body =
+            fs::read(&path).map_err(|err| format!("body file {}: {}", path.display(), err))?;
+        built.with_body(body);
+    }
+
+    if let Some(delay) = response["fixedDelayMilliseconds"].as_u64() {
+        built.with_delay(Duration::from_millis(delay));
+    }
+    let distribution = &response["delayDistribution"];
+    if !distribution.is_null() {
+        match (
+            distribution["type"].as_str(),
+            distribution["lower"].as_u64(),
+            distribution["upper"].as_u64(),
+        ) {
+            (Some("uniform"), Some(lower), Some(upper)) if lower <= upper => {
+                built.with_delay_range(Duration::from_millis(lower), Duration::from_millis(upper));
+            }
+            _ => return Err("unsupported `delayDistribution`, only uniform is".to_string()),
+        }
+    }
+    if let Some(fault) = response["fault"].as_str() {
+        built.with_fault(match fault {
+            "CONNECTION_RESET_BY_PEER" => Fault::ConnectionReset,
+            "EMPTY_RESPONSE" => Fault::EmptyResponse,
+            "RANDOM_DATA_THEN_CLOSE" | "MALFORMED_RESPONSE_CHUNK" => Fault::GarbageBytes,
+            _ => return Err(format!("unsupported fault `{}`", fault)),
+        });
+    }
+    Ok(built)
+}
+
+/// Translates a WireMock value pattern, such as `{ "equalTo": "value" }`
+fn matcher_of(pattern: &JsonValue) -> Result<Matcher, String> {
+    let case_insensitive = pattern["caseInsensitive"].as_bool() == Some(true);
+    let (kind, value) = pattern
+        .entries()
+        .find(|(key, _)| *key != "caseInsensitive" && *key != "ignoreExtraElements")
+        .ok_or("empty pattern")?;
+    let text = || {
+        value
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| format!("`{}` should be a string", kind))
+    };
+
+    let matcher = match kind {
+        "equalTo" if case_insensitive => {
+            Matcher::Regex(format!("(?i)^{}$", regex::escape(&text()?)))
+        }
+        "equalTo" => Matcher::Exact(text()?),
+        "contains" => Matcher::Contains(text()?),
+        "matches" => Matcher::Regex(format!("^(?:{})$", text()?)),
+        "absent" if value.as_bool() == Some(true) => Matcher::Absent,
+        "binaryEqualTo" => Matcher::Bytes(BASE64.decode(text()?).map_err(|err| err.to_string())?),
+        "equalToJson" => {
+            // given either as JSON, or as a string holding it
+            let expected = match value.as_str() {
+                Some(text) => json::parse(text).map_err(|err| err.to_string())?,
+                None => value.clone(),
+            };
+            if pattern["ignoreExtraElements"].as_bool() == Some(true) {
+                Matcher::PartialJson(expected)
+            } else {
+                Matcher::Json(expected)
+            }
+        }
+        _ => return Err(format!("unsupported matcher `{}`", kind)),
+    };
+    if case_insensitive && kind != "equalTo" {
+        return Err(format!("unsupported `caseInsensitive` for `{}`", kind));
+    }
+    matcher.validate().map_err(|err| err.to_string())?;
+    Ok(matcher)
+}
+
+/// Fails on the first key of `object` that isn't `known`
+fn supported(object: &JsonValue, known: &[&str], what: &str) -> Result<(), String> {
+    if !object.is_object() {
+        return Err(format!("{} should be an object", what));
+    }
+    match object.entries().find(|(key, _)| !known.contains(key)) {
+        Some((key, _)) => Err(format!("unsupported `{}` in {}", key, what)),
+        None => Ok(()),
+    }
+}